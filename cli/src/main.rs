@@ -1,7 +1,12 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use localtunnel_client::{broadcast, open_tunnel, ClientConfig};
-use localtunnel_server::{start, ServerConfig};
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use localtunnel_client::{broadcast, start_tunnel, ClientConfig, VisitorAuth};
+use localtunnel_server::{
+    auth::{self, token::Claims},
+    start, AccessLogConfig, AccessLogFormat, Auth, CfWorkerStore, FileStore, IpNet, Limits,
+    ServerConfig, Timings, TokenAuth, TunnelType,
+};
+use std::{net::IpAddr, path::PathBuf, time::Duration};
 use tokio::signal;
 
 mod config;
@@ -21,9 +26,9 @@ enum Command {
         /// Address of proxy server
         #[clap(long)]
         host: String,
-        /// Subdomain of the proxied url
+        /// Subdomain of the proxied url, a random one is assigned if not provided.
         #[clap(long)]
-        subdomain: String,
+        subdomain: Option<String>,
         /// The local host to expose.
         #[clap(long, default_value = "127.0.0.1")]
         local_host: String,
@@ -35,6 +40,39 @@ enum Command {
         max_conn: u8,
         #[clap(long)]
         credential: Option<String>,
+        /// Multiplex all traffic over a single connection if the server supports it.
+        #[clap(long)]
+        multiplex: bool,
+        /// Expose the local port as raw tcp on a public port of the server, e.g. for ssh.
+        #[clap(long)]
+        tcp: bool,
+        /// Require visitors to log in with HTTP Basic auth as user:password, may be repeated.
+        #[clap(long)]
+        basic_auth: Vec<String>,
+        /// Require visitors to send Authorization: Bearer with the token, may be repeated.
+        #[clap(long)]
+        bearer_token: Vec<String>,
+        /// CIDRs or addresses of the only visitors allowed, e.g. 10.0.0.0/8,203.0.113.7, all if not set.
+        #[clap(long, value_delimiter = ',', value_parser = parse_ip_net)]
+        allow_ip: Vec<IpNet>,
+        /// CIDRs or addresses of visitors refused, even if they're allowed.
+        #[clap(long, value_delimiter = ',', value_parser = parse_ip_net)]
+        deny_ip: Vec<IpNet>,
+        /// The local service speaks HTTP/2 without TLS, e.g. gRPC.
+        #[clap(long)]
+        http2: bool,
+        /// Seconds the server keeps the tunnel unused, capped by its maximum, the server's default if not set.
+        #[clap(long)]
+        cleanup_timeout: Option<u64>,
+        /// Megabytes of a visitor request body, capped by the server's limit.
+        #[clap(long, value_parser = parse_megabytes)]
+        max_body_size: Option<u64>,
+        /// Seconds the server waits for the response of the local service, capped by its limit.
+        #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+        upstream_timeout: Option<u64>,
+        /// Seconds an upgraded connection, e.g. a WebSocket, may be idle, capped by the server's limit.
+        #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+        upgrade_idle_timeout: Option<u64>,
     },
 
     /// Starts proxy server to accept user connections and proxy setup connection.
@@ -56,9 +94,209 @@ enum Command {
         proxy_port: u16,
         #[clap(long)]
         require_auth: bool,
+        /// Where the credentials of tunnel clients are checked if --require-auth is set.
+        #[clap(long, value_enum, default_value = "cloudflare")]
+        auth_backend: AuthBackend,
+        /// Credentials file of the file auth backend, managed with the credential command.
+        #[clap(long, required_if_eq("auth_backend", "file"))]
+        credentials_file: Option<PathBuf>,
+        /// Secret the tokens of the token auth backend are signed with.
+        #[clap(
+            long,
+            env = "LOCALTUNNEL_TOKEN_SECRET",
+            hide_env_values = true,
+            required_if_eq("auth_backend", "token")
+        )]
+        token_secret: Option<String>,
+        /// Refuse credentials in the query string of old clients instead of the Authorization header.
+        #[clap(long)]
+        reject_query_credential: bool,
+        /// The port to accept multiplexed client connections, disabled if not set.
+        #[clap(long)]
+        mux_port: Option<u16>,
+        /// Maximum number of user requests each tunnel queues while all its sockets are busy.
+        #[clap(long, default_value = "32")]
+        queue_depth: usize,
+        /// Seconds a queued user request waits for a socket.
+        #[clap(long, default_value = "10")]
+        queue_timeout: u64,
+        /// Directory of html templates overriding the error pages, e.g. `404.html`, `error.html`.
+        #[clap(long)]
+        error_pages: Option<PathBuf>,
+        /// Keep X-Forwarded-* and Forwarded headers from a proxy in front of the server, e.g. Caddy.
+        #[clap(long)]
+        trust_forwarded_headers: bool,
+        /// Read the user address from a PROXY protocol v1/v2 header on the proxy port.
+        #[clap(long)]
+        proxy_protocol: bool,
+        /// CIDRs required to send the PROXY protocol header, e.g. 127.0.0.1/32, all if not set.
+        #[clap(long, value_delimiter = ',')]
+        proxy_protocol_trusted: Vec<IpNet>,
+        /// Directory with fullchain.pem and privkey.pem to serve https without a proxy in front, implies --secure.
+        #[clap(long)]
+        tls_dir: Option<PathBuf>,
+        /// Seconds an unused tunnel is kept before it's cleaned up.
+        #[clap(long, default_value = "3600")]
+        cleanup_timeout: u64,
+        /// Maximum seconds a tunnel may ask to be kept unused.
+        #[clap(long, default_value = "86400")]
+        max_cleanup_timeout: u64,
+        /// Seconds between the cleanups of unused tunnels.
+        #[clap(long, default_value = "60", value_parser = clap::value_parser!(u64).range(1..))]
+        cleanup_interval: u64,
+        /// Seconds a tunnel port waits for a new client socket before it drops disconnected ones.
+        #[clap(long, default_value = "20", value_parser = clap::value_parser!(u64).range(1..))]
+        accept_timeout: u64,
+        /// Idle seconds of a client socket before TCP keepalive probes are sent.
+        #[clap(long, default_value = "30")]
+        keepalive_time: u64,
+        /// Seconds between TCP keepalive probes.
+        #[clap(long, default_value = "10")]
+        keepalive_interval: u64,
+        /// Unanswered TCP keepalive probes before a client socket is closed.
+        #[clap(long, default_value = "5")]
+        keepalive_retries: u32,
+        /// Kilobytes of the headers of a visitor request, at least 8.
+        #[clap(long, default_value = "64", value_parser = parse_kilobytes)]
+        max_header_size: usize,
+        /// Seconds a visitor has to send the request headers.
+        #[clap(long, default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+        header_read_timeout: u64,
+        /// Maximum megabytes of a visitor request body.
+        #[clap(long, default_value = "100", value_parser = parse_megabytes)]
+        max_body_size: u64,
+        /// Maximum seconds to wait for the response of a local service.
+        #[clap(long, default_value = "60", value_parser = clap::value_parser!(u64).range(1..))]
+        upstream_timeout: u64,
+        /// Maximum seconds an upgraded connection, e.g. a WebSocket, may be idle.
+        #[clap(long, default_value = "3600", value_parser = clap::value_parser!(u64).range(1..))]
+        upgrade_idle_timeout: u64,
+        /// File to log every user request to, disabled if not set.
+        #[clap(long)]
+        access_log: Option<PathBuf>,
+        /// Format of the access log: json, common or combined.
+        #[clap(long, default_value = "json")]
+        access_log_format: AccessLogFormat,
+        /// Megabytes the access log grows to before it's rotated.
+        #[clap(long, default_value = "100", value_parser = parse_megabytes)]
+        access_log_max_size: u64,
+        /// Rotated access logs kept.
+        #[clap(long, default_value = "5")]
+        access_log_max_files: usize,
+        /// Serve Prometheus metrics at `/metrics` on the api port.
+        #[clap(long)]
+        metrics: bool,
+    },
+
+    /// Manages the credentials file of the file auth backend.
+    Credential {
+        /// The credentials file, created if it doesn't exist.
+        #[clap(long)]
+        file: PathBuf,
+        #[clap(subcommand)]
+        action: CredentialAction,
+    },
+
+    /// Manages tokens of the token auth backend.
+    Token {
+        #[clap(subcommand)]
+        action: TokenAction,
     },
 }
 
+#[derive(Subcommand)]
+enum TokenAction {
+    /// Prints a new token signed with the secret of the server.
+    Issue {
+        /// Secret the server validates tokens with.
+        #[clap(long, env = "LOCALTUNNEL_TOKEN_SECRET", hide_env_values = true)]
+        secret: String,
+        /// Subdomains the token may register, `*` matches any chars, e.g. ci-*.
+        #[clap(long)]
+        subdomain: String,
+        /// Seconds until the token expires.
+        #[clap(long, default_value = "3600")]
+        ttl: u64,
+        /// Maximum number of sockets the tunnel may open.
+        #[clap(long)]
+        max_sockets: Option<u8>,
+        /// The only tunnel type allowed, http or tcp, any if not set.
+        #[clap(long = "type")]
+        tunnel_type: Option<TunnelType>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CredentialAction {
+    /// Generates a credential for the subdomain, `*` matches any chars, e.g. dev-*.
+    Add { subdomain: String },
+    /// Removes the credential of the subdomain.
+    Remove { subdomain: String },
+    /// Replaces the credential of the subdomain with a new one.
+    Rotate { subdomain: String },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum AuthBackend {
+    /// Cloudflare KV, configured by the CLOUDFLARE_* environment variables.
+    Cloudflare,
+    /// Hashed credentials in a local file, see --credentials-file.
+    File,
+    /// Signed tokens issued by the token command, see --token-secret.
+    Token,
+    /// Accept any credential.
+    None,
+}
+
+/// A CIDR, or a single address as its host network.
+fn parse_ip_net(value: &str) -> Result<IpNet, String> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{value} is not a valid CIDR or address"))
+}
+
+/// A size in kilobytes as bytes.
+fn parse_kilobytes(value: &str) -> Result<usize, String> {
+    let kilobytes: usize = value
+        .parse()
+        .map_err(|_| format!("{value} is not a number of kilobytes"))?;
+    kilobytes
+        .checked_mul(1024)
+        .ok_or_else(|| format!("{value} kilobytes is too large"))
+}
+
+/// A size in megabytes as bytes.
+fn parse_megabytes(value: &str) -> Result<u64, String> {
+    let megabytes: u64 = value
+        .parse()
+        .map_err(|_| format!("{value} is not a number of megabytes"))?;
+    megabytes
+        .checked_mul(1024 * 1024)
+        .ok_or_else(|| format!("{value} megabytes is too large"))
+}
+
+impl AuthBackend {
+    fn build(
+        self,
+        credentials_file: Option<PathBuf>,
+        token_secret: Option<String>,
+    ) -> Result<Box<dyn Auth + Send + Sync>> {
+        Ok(match self {
+            AuthBackend::Cloudflare => Box::new(CfWorkerStore::new()?),
+            AuthBackend::File => {
+                let path = credentials_file.context("--credentials-file is required")?;
+                Box::new(FileStore::open(&path)?)
+            }
+            AuthBackend::Token => {
+                let secret = token_secret.context("--token-secret is required")?;
+                Box::new(TokenAuth::new(secret))
+            }
+            AuthBackend::None => Box::new(()),
+        })
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     config::setup();
@@ -74,22 +312,50 @@ async fn main() -> Result<()> {
             port,
             max_conn,
             credential,
+            multiplex,
+            tcp,
+            basic_auth,
+            bearer_token,
+            allow_ip,
+            deny_ip,
+            cleanup_timeout,
+            http2,
+            max_body_size,
+            upstream_timeout,
+            upgrade_idle_timeout,
         } => {
             let (notify_shutdown, _) = broadcast::channel(1);
             let config = ClientConfig {
                 server: Some(host),
-                subdomain: Some(subdomain),
+                subdomain,
                 local_host: Some(local_host),
                 local_port: port,
                 shutdown_signal: notify_shutdown.clone(),
                 max_conn,
                 credential,
                 reregister_after: None,
+                multiplex,
+                tcp,
+                http2,
+                visitor_auth: (!basic_auth.is_empty() || !bearer_token.is_empty()).then_some(
+                    VisitorAuth {
+                        basic: basic_auth,
+                        bearer: bearer_token,
+                    },
+                ),
+                allow_ips: allow_ip.iter().map(IpNet::to_string).collect(),
+                deny_ips: deny_ip.iter().map(IpNet::to_string).collect(),
+                cleanup_timeout: cleanup_timeout.map(Duration::from_secs),
+                max_body_size,
+                upstream_timeout: upstream_timeout.map(Duration::from_secs),
+                upgrade_idle_timeout: upgrade_idle_timeout.map(Duration::from_secs),
             };
-            let result = open_tunnel(config).await?;
-            log::info!("Tunnel url: {:?}", result);
+            let tunnel = start_tunnel(config).await?;
+            log::info!("Tunnel url: {:?}", tunnel.url);
 
             signal::ctrl_c().await?;
+            let _ = notify_shutdown.send(());
+            tunnel.closed().await;
             log::info!("Quit");
         }
         Command::Server {
@@ -99,6 +365,35 @@ async fn main() -> Result<()> {
             max_sockets,
             proxy_port,
             require_auth,
+            auth_backend,
+            credentials_file,
+            token_secret,
+            reject_query_credential,
+            mux_port,
+            queue_depth,
+            queue_timeout,
+            error_pages,
+            trust_forwarded_headers,
+            proxy_protocol,
+            proxy_protocol_trusted,
+            tls_dir,
+            cleanup_timeout,
+            max_cleanup_timeout,
+            cleanup_interval,
+            accept_timeout,
+            keepalive_time,
+            keepalive_interval,
+            keepalive_retries,
+            max_header_size,
+            header_read_timeout,
+            max_body_size,
+            upstream_timeout,
+            upgrade_idle_timeout,
+            access_log,
+            access_log_format,
+            access_log_max_size,
+            access_log_max_files,
+            metrics,
         } => {
             let config = ServerConfig {
                 domain,
@@ -107,9 +402,70 @@ async fn main() -> Result<()> {
                 max_sockets,
                 proxy_port,
                 require_auth,
+                auth: Some(auth_backend.build(credentials_file, token_secret)?),
+                reject_query_credential,
+                mux_port,
+                queue_depth,
+                queue_timeout: Duration::from_secs(queue_timeout),
+                error_pages,
+                trust_forwarded_headers,
+                proxy_protocol,
+                proxy_protocol_trusted,
+                tls_dir,
+                timings: Timings {
+                    cleanup_timeout: Duration::from_secs(cleanup_timeout),
+                    max_cleanup_timeout: Duration::from_secs(max_cleanup_timeout),
+                    cleanup_interval: Duration::from_secs(cleanup_interval),
+                    accept_timeout: Duration::from_secs(accept_timeout),
+                    keepalive_time: Duration::from_secs(keepalive_time),
+                    keepalive_interval: Duration::from_secs(keepalive_interval),
+                    keepalive_retries,
+                },
+                limits: Limits {
+                    max_header_size,
+                    header_read_timeout: Duration::from_secs(header_read_timeout),
+                    max_body_size,
+                    upstream_timeout: Duration::from_secs(upstream_timeout),
+                    upgrade_idle_timeout: Duration::from_secs(upgrade_idle_timeout),
+                },
+                access_log: access_log.map(|path| AccessLogConfig {
+                    path,
+                    format: access_log_format,
+                    max_size: access_log_max_size,
+                    max_files: access_log_max_files,
+                }),
+                metrics,
             };
             start(config).await?;
         }
+        Command::Credential { file, action } => match action {
+            CredentialAction::Add { subdomain } => {
+                let credential = auth::file::add(&file, &subdomain)?;
+                println!("{credential}");
+            }
+            CredentialAction::Remove { subdomain } => auth::file::remove(&file, &subdomain)?,
+            CredentialAction::Rotate { subdomain } => {
+                let credential = auth::file::rotate(&file, &subdomain)?;
+                println!("{credential}");
+            }
+        },
+        Command::Token { action } => match action {
+            TokenAction::Issue {
+                secret,
+                subdomain,
+                ttl,
+                max_sockets,
+                tunnel_type,
+            } => {
+                let claims = Claims {
+                    sub: subdomain,
+                    exp: auth::token::now() + ttl,
+                    max_sockets,
+                    tunnel_type,
+                };
+                println!("{}", TokenAuth::new(secret).issue(&claims)?);
+            }
+        },
     }
 
     Ok(())
@@ -1,21 +1,42 @@
+use std::future::poll_fn;
 use std::sync::{
     atomic::{AtomicU64, Ordering},
     Arc,
 };
 use std::time::Instant;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use socket2::{SockRef, TcpKeepalive};
-use tokio::io;
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 pub use tokio::sync::broadcast;
 use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
 use tokio::time::{sleep, Duration};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
 
 pub const PROXY_SERVER: &str = "https://your-domain.com";
 pub const LOCAL_HOST: &str = "127.0.0.1";
 
+/// Transport name for multiplexing visitor streams over a single connection.
+const MULTIPLEX_TRANSPORT: &str = "multiplex";
+/// Tunnel type exposing the local port on a public tcp port of the server.
+const TCP_TUNNEL: &str = "tcp";
+/// Upstream protocol of local services speaking HTTP/2 without TLS.
+const H2_UPSTREAM: &str = "h2";
+
+/// Registration headers carrying the hashed credentials of visitors.
+const VISITOR_BASIC_AUTH: &str = "x-visitor-basic-auth";
+const VISITOR_BEARER_TOKENS: &str = "x-visitor-bearer-tokens";
+/// Header with the secret of the previous registration, so the server hands
+/// the same url back to this client or closes the tunnel.
+const TUNNEL_SECRET: &str = "x-tunnel-secret";
+/// How long closing the tunnel on shutdown may take.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 // See https://tldp.org/HOWTO/html_single/TCP-Keepalive-HOWTO to understand how keepalive work.
 const TCP_KEEPALIVE_TIME: Duration = Duration::from_secs(30);
 const TCP_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
@@ -36,15 +57,31 @@ struct ProxyResponse {
     port: u16,
     max_conn_count: u8,
     url: String,
+    /// Servers not authenticating tunnel sockets don't send it.
+    #[serde(default)]
+    secret: Option<String>,
+    /// The server expects the secret on every socket, as requested.
+    #[serde(default)]
+    handshake: bool,
+    #[serde(default)]
+    transport: Option<String>,
 }
 
 /// The server detail for client to connect
 #[derive(Clone, Debug)]
 pub struct TunnelServerInfo {
+    pub id: String,
     pub host: String,
     pub port: u16,
     pub max_conn_count: u8,
     pub url: String,
+    /// Proves the client when it re-registers or closes the tunnel.
+    pub secret: Option<String>,
+    /// The secret is presented on every connection to the server port before
+    /// it's used for proxying.
+    pub handshake: bool,
+    /// Visitor streams are multiplexed over a single connection to `port`.
+    pub multiplex: bool,
 }
 
 pub struct ClientConfig {
@@ -58,6 +95,54 @@ pub struct ClientConfig {
     /// How long the remote endpoint must be continuously unreachable before the
     /// tunnel re-registers. `None` uses [`DEFAULT_REREGISTER_AFTER`].
     pub reregister_after: Option<Duration>,
+    /// Multiplex all visitor streams over a single connection to the server,
+    /// falls back to a pool of `max_conn` sockets if the server doesn't support it.
+    pub multiplex: bool,
+    /// Expose the local port as raw tcp on a public port of the server instead of
+    /// http routed by subdomain, e.g. for ssh or databases. The url is `tcp://host:port`.
+    pub tcp: bool,
+    /// The local service speaks HTTP/2 without TLS (h2c), e.g. gRPC, so the server
+    /// proxies requests as HTTP/2 instead of HTTP/1.1.
+    pub http2: bool,
+    /// Credentials visitors must present, the tunnel is public if `None`.
+    pub visitor_auth: Option<VisitorAuth>,
+    /// CIDRs or addresses of the only visitors allowed, all if empty.
+    pub allow_ips: Vec<String>,
+    /// CIDRs or addresses of visitors refused, even if they're allowed.
+    pub deny_ips: Vec<String>,
+    /// How long the server keeps the tunnel unused before it's cleaned up, capped
+    /// by the server's maximum. `None` uses the server's default.
+    pub cleanup_timeout: Option<Duration>,
+    /// Bytes of a visitor request body, larger requests get a 413. Like the
+    /// timeouts below, capped by the server's limit, which is used if `None`.
+    pub max_body_size: Option<u64>,
+    /// How long the server waits for the response headers of the local service.
+    pub upstream_timeout: Option<Duration>,
+    /// How long an upgraded connection, e.g. a WebSocket, may be idle.
+    pub upgrade_idle_timeout: Option<Duration>,
+}
+
+/// Credentials the server requires from visitors before proxying their requests,
+/// only their sha256 is sent to the server.
+#[derive(Clone, Debug, Default)]
+pub struct VisitorAuth {
+    /// `user:password` pairs accepted with HTTP Basic auth.
+    pub basic: Vec<String>,
+    /// Tokens accepted with `Authorization: Bearer`.
+    pub bearer: Vec<String>,
+}
+
+/// A registered tunnel, maintained until the shutdown signal is sent.
+pub struct Tunnel {
+    pub url: String,
+    supervisor: JoinHandle<()>,
+}
+
+impl Tunnel {
+    /// Wait until the tunnel is closed on the server after the shutdown signal.
+    pub async fn closed(self) {
+        let _ = self.supervisor.await;
+    }
 }
 
 /// Open tunnels directly between server and localhost.
@@ -66,6 +151,12 @@ pub struct ClientConfig {
 /// connections and automatically re-registers if the endpoint becomes
 /// unreachable.
 pub async fn open_tunnel(config: ClientConfig) -> Result<String> {
+    Ok(start_tunnel(config).await?.url)
+}
+
+/// Like [`open_tunnel`], but returns a handle to wait for the tunnel to be
+/// closed on the server once the shutdown signal is sent.
+pub async fn start_tunnel(config: ClientConfig) -> Result<Tunnel> {
     let ClientConfig {
         server,
         subdomain,
@@ -75,30 +166,73 @@ pub async fn open_tunnel(config: ClientConfig) -> Result<String> {
         max_conn,
         credential,
         reregister_after,
+        multiplex,
+        tcp,
+        http2,
+        visitor_auth,
+        allow_ips,
+        deny_ips,
+        cleanup_timeout,
+        max_body_size,
+        upstream_timeout,
+        upgrade_idle_timeout,
     } = config;
-    let tunnel_info =
-        get_tunnel_endpoint(server.clone(), subdomain.clone(), credential.clone()).await?;
-    let url = tunnel_info.url.clone();
-
-    let supervisor_config = SupervisorConfig {
+    let registration = Registration {
         server,
         subdomain,
         credential,
+        multiplex,
+        tcp,
+        http2,
+        visitor_auth,
+        allow_ips,
+        deny_ips,
+        cleanup_timeout,
+        max_body_size,
+        upstream_timeout,
+        upgrade_idle_timeout,
+    };
+    let tunnel_info = get_tunnel_endpoint(&registration, None).await?;
+    let url = tunnel_info.url.clone();
+
+    let supervisor_config = SupervisorConfig {
+        registration,
         local_host,
         local_port,
         shutdown_signal,
         max_conn,
         reregister_after: reregister_after.unwrap_or(DEFAULT_REREGISTER_AFTER),
     };
-    tokio::spawn(tunnel_supervisor(supervisor_config, tunnel_info));
-
-    Ok(url)
+    // subscribed before spawning, so a signal sent right away isn't missed
+    let shutdown_rx = supervisor_config.shutdown_signal.subscribe();
+    let supervisor = tokio::spawn(tunnel_supervisor(
+        supervisor_config,
+        tunnel_info,
+        shutdown_rx,
+    ));
+
+    Ok(Tunnel { url, supervisor })
 }
 
-struct SupervisorConfig {
+/// What the tunnel is registered with, kept to re-register it.
+struct Registration {
     server: Option<String>,
     subdomain: Option<String>,
     credential: Option<String>,
+    multiplex: bool,
+    tcp: bool,
+    http2: bool,
+    visitor_auth: Option<VisitorAuth>,
+    allow_ips: Vec<String>,
+    deny_ips: Vec<String>,
+    cleanup_timeout: Option<Duration>,
+    max_body_size: Option<u64>,
+    upstream_timeout: Option<Duration>,
+    upgrade_idle_timeout: Option<Duration>,
+}
+
+struct SupervisorConfig {
+    registration: Registration,
     local_host: Option<String>,
     local_port: u16,
     shutdown_signal: broadcast::Sender<()>,
@@ -113,9 +247,12 @@ struct SupervisorConfig {
 // cleaned up our listener port, or the network path changed), connection tasks
 // signal via `reregister_tx` and the supervisor requests a fresh endpoint from
 // the API server—using the same subdomain so the public tunnel URL stays stable.
-async fn tunnel_supervisor(config: SupervisorConfig, initial_info: TunnelServerInfo) {
+async fn tunnel_supervisor(
+    config: SupervisorConfig,
+    initial_info: TunnelServerInfo,
+    mut shutdown_rx: broadcast::Receiver<()>,
+) {
     let mut current_info = initial_info;
-    let mut shutdown_rx = config.shutdown_signal.subscribe();
     let reregister_after = config.reregister_after;
 
     loop {
@@ -127,14 +264,24 @@ async fn tunnel_supervisor(config: SupervisorConfig, initial_info: TunnelServerI
         let (reregister_tx, mut reregister_rx) = mpsc::channel::<()>(1);
         let health = RoundHealth::new(reregister_after, reregister_tx);
 
-        start_tunnel_connections(
-            &current_info,
-            config.local_host.clone(),
-            config.local_port,
-            round_stop_tx.clone(),
-            config.max_conn,
-            health,
-        );
+        if current_info.multiplex {
+            start_multiplexed_connection(
+                &current_info,
+                config.local_host.clone(),
+                config.local_port,
+                round_stop_tx.clone(),
+                health,
+            );
+        } else {
+            start_tunnel_connections(
+                &current_info,
+                config.local_host.clone(),
+                config.local_port,
+                round_stop_tx.clone(),
+                config.max_conn,
+                health,
+            );
+        }
 
         // Block until either the connections ask for re-registration or we
         // are told to shut down entirely.
@@ -149,22 +296,18 @@ async fn tunnel_supervisor(config: SupervisorConfig, initial_info: TunnelServerI
             }
             _ = shutdown_rx.recv() => {
                 let _ = round_stop_tx.send(());
+                close_tunnel(&config.registration, &current_info).await;
                 return;
             }
         }
 
         // Re-register with exponential backoff (2 s → 4 s → … → 60 s cap).
-        // The same subdomain is requested so the public URL doesn't change;
-        // only the internal listener port is refreshed.
+        // The same subdomain is requested, along with the previous secret proving
+        // we own it, so the public URL doesn't change; only the internal listener
+        // port is refreshed.
         let mut backoff = Duration::from_secs(2);
         loop {
-            match get_tunnel_endpoint(
-                config.server.clone(),
-                config.subdomain.clone(),
-                config.credential.clone(),
-            )
-            .await
-            {
+            match get_tunnel_endpoint(&config.registration, Some(&current_info)).await {
                 Ok(info) => {
                     log::info!("Re-registered tunnel endpoint: {:?}", info);
                     current_info = info;
@@ -180,7 +323,10 @@ async fn tunnel_supervisor(config: SupervisorConfig, initial_info: TunnelServerI
                         _ = sleep(backoff) => {
                             backoff = (backoff * 2).min(Duration::from_secs(60));
                         }
-                        _ = shutdown_rx.recv() => return,
+                        _ = shutdown_rx.recv() => {
+                            close_tunnel(&config.registration, &current_info).await;
+                            return;
+                        }
                     }
                 }
             }
@@ -252,6 +398,7 @@ fn start_tunnel_connections(
 ) {
     let server_host = server.host.clone();
     let server_port = server.port;
+    let secret = server.secret.clone().filter(|_| server.handshake);
     let local_host = local_host.unwrap_or_else(|| LOCAL_HOST.to_string());
 
     let count = std::cmp::min(server.max_conn_count, max_conn);
@@ -272,6 +419,7 @@ fn start_tunnel_connections(
                         },
                     };
                     let server_host = server_host.clone();
+                    let secret = secret.clone();
                     let local_host = local_host.clone();
                     let health = health.clone();
                     let mut shutdown_receiver = shutdown_signal.subscribe();
@@ -280,6 +428,7 @@ fn start_tunnel_connections(
                         tokio::select! {
                             _ = tunnel_one_connection(
                                 &server_host, server_port,
+                                secret.as_deref(),
                                 &local_host, local_port,
                                 &health,
                             ) => {}
@@ -303,12 +452,14 @@ fn start_tunnel_connections(
 async fn tunnel_one_connection(
     server_host: &str,
     server_port: u16,
+    secret: Option<&str>,
     local_host: &str,
     local_port: u16,
     health: &RoundHealth,
 ) {
     log::debug!("Connecting to remote: {}:{}", server_host, server_port);
-    let remote_stream = match TcpStream::connect(format!("{server_host}:{server_port}")).await {
+    let handshake = secret.map(|secret| format!("{secret}\n"));
+    let remote_stream = match connect_remote(server_host, server_port, handshake).await {
         Ok(stream) => {
             health.record_success();
             stream
@@ -334,40 +485,194 @@ async fn tunnel_one_connection(
     }
 }
 
-async fn proxy_through(
-    mut remote_stream: TcpStream,
+/// Keep a single control connection to the server's mux port open, and proxy
+/// every stream the server opens over it to the local server.
+fn start_multiplexed_connection(
+    server: &TunnelServerInfo,
+    local_host: Option<String>,
+    local_port: u16,
+    shutdown_signal: broadcast::Sender<()>,
+    health: RoundHealth,
+) {
+    let server = server.clone();
+    let local_host = local_host.unwrap_or_else(|| LOCAL_HOST.to_string());
+    let mut shutdown_receiver = shutdown_signal.subscribe();
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = multiplex_one_connection(&server, &local_host, local_port, &health) => {}
+                _ = shutdown_receiver.recv() => {
+                    log::info!("Shutting down multiplexed connection");
+                    return;
+                }
+            }
+        }
+    });
+}
+
+async fn multiplex_one_connection(
+    server: &TunnelServerInfo,
     local_host: &str,
     local_port: u16,
-) -> Result<()> {
-    log::debug!("Connecting to local: {}:{}", local_host, local_port);
-    let mut local_stream = TcpStream::connect(format!("{local_host}:{local_port}")).await?;
+    health: &RoundHealth,
+) {
+    log::debug!(
+        "Connecting multiplexed to remote: {}:{}",
+        server.host,
+        server.port
+    );
+    let handshake = format!(
+        "{} {}\n",
+        server.id,
+        server.secret.as_deref().unwrap_or_default()
+    );
+    let remote_stream = match connect_remote(&server.host, server.port, Some(handshake)).await {
+        Ok(stream) => {
+            health.record_success();
+            stream
+        }
+        Err(err) => {
+            let down_for = health.record_failure();
+            log::error!("Remote connect failed (down for {:?}): {:?}", down_for, err);
+            sleep(Duration::from_secs(10)).await;
+            return;
+        }
+    };
+
+    let mut connection = yamux::Connection::new(
+        remote_stream.compat(),
+        yamux::Config::default(),
+        yamux::Mode::Client,
+    );
+    loop {
+        match poll_fn(|cx| connection.poll_next_inbound(cx)).await {
+            Some(Ok(stream)) => {
+                let local_host = local_host.to_string();
+                tokio::spawn(async move {
+                    if let Err(err) = proxy_through(stream.compat(), &local_host, local_port).await
+                    {
+                        log::error!("Proxy error: {:?}", err);
+                    }
+                });
+            }
+            Some(Err(err)) => {
+                log::error!("Multiplexed connection failed: {:?}", err);
+                break;
+            }
+            None => break,
+        }
+    }
+
+    // Same as for a single socket, the remote was reachable until now.
+    health.record_success();
+    sleep(Duration::from_secs(1)).await;
+}
+
+/// Connect to the server port with TCP keepalive enabled, and authenticate the
+/// connection by sending the handshake if the server issued a secret.
+async fn connect_remote(
+    server_host: &str,
+    server_port: u16,
+    handshake: Option<String>,
+) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(format!("{server_host}:{server_port}")).await?;
 
     let ka = TcpKeepalive::new()
         .with_time(TCP_KEEPALIVE_TIME)
         .with_interval(TCP_KEEPALIVE_INTERVAL);
     #[cfg(not(target_os = "windows"))]
     let ka = ka.with_retries(TCP_KEEPALIVE_RETRIES);
-    let sf = SockRef::from(&remote_stream);
+    let sf = SockRef::from(&stream);
     sf.set_tcp_keepalive(&ka)?;
 
+    if let Some(handshake) = handshake {
+        stream.write_all(handshake.as_bytes()).await?;
+    }
+    Ok(stream)
+}
+
+async fn proxy_through<S: AsyncRead + AsyncWrite + Unpin>(
+    mut remote_stream: S,
+    local_host: &str,
+    local_port: u16,
+) -> Result<()> {
+    log::debug!("Connecting to local: {}:{}", local_host, local_port);
+    let mut local_stream = TcpStream::connect(format!("{local_host}:{local_port}")).await?;
+
     io::copy_bidirectional(&mut remote_stream, &mut local_stream).await?;
     Ok(())
 }
 
+/// Register the tunnel, `previous` is the registration of the same tunnel when it re-registers.
 async fn get_tunnel_endpoint(
-    server: Option<String>,
-    subdomain: Option<String>,
-    credential: Option<String>,
+    registration: &Registration,
+    previous: Option<&TunnelServerInfo>,
 ) -> Result<TunnelServerInfo> {
-    let server = server.as_deref().unwrap_or(PROXY_SERVER);
-    let assigned_domain = subdomain.as_deref().unwrap_or("?new");
-    let mut uri = format!("{}/{}", server, assigned_domain);
-    if let Some(credential) = credential {
-        uri = format!("{}?credential={}", uri, credential);
-    }
+    let server = registration.server.as_deref().unwrap_or(PROXY_SERVER);
+    let assigned_domain = registration
+        .subdomain
+        .as_deref()
+        .or(previous.map(|info| info.id.as_str()))
+        .unwrap_or("?new");
+    let uri = format!("{}/{}", server, assigned_domain);
     log::info!("Request for assign domain: {}", uri);
 
-    let resp = reqwest::get(uri).await?.json::<ProxyResponse>().await?;
+    // sockets authenticate with the secret, plain sockets are only accepted for
+    // clients like the upstream Node one which don't send it
+    let mut query = vec![("handshake", "true".to_string())];
+    if registration.multiplex {
+        query.push(("transport", MULTIPLEX_TRANSPORT.to_string()));
+    }
+    if registration.tcp {
+        query.push(("type", TCP_TUNNEL.to_string()));
+    }
+    if registration.http2 {
+        query.push(("upstream", H2_UPSTREAM.to_string()));
+    }
+    if !registration.allow_ips.is_empty() {
+        query.push(("allow", registration.allow_ips.join(",")));
+    }
+    if !registration.deny_ips.is_empty() {
+        query.push(("deny", registration.deny_ips.join(",")));
+    }
+    if let Some(cleanup_timeout) = registration.cleanup_timeout {
+        query.push(("cleanup_timeout", cleanup_timeout.as_secs().to_string()));
+    }
+    if let Some(max_body_size) = registration.max_body_size {
+        query.push(("max_body_size", max_body_size.to_string()));
+    }
+    if let Some(upstream_timeout) = registration.upstream_timeout {
+        query.push(("upstream_timeout", upstream_timeout.as_secs().to_string()));
+    }
+    if let Some(upgrade_idle_timeout) = registration.upgrade_idle_timeout {
+        query.push((
+            "upgrade_idle_timeout",
+            upgrade_idle_timeout.as_secs().to_string(),
+        ));
+    }
+
+    let mut request = reqwest::Client::new().get(uri).query(&query);
+    // kept out of the url, which may end up in access logs
+    if let Some(credential) = &registration.credential {
+        request = request.bearer_auth(credential);
+    }
+    if let Some(secret) = previous.and_then(|info| info.secret.as_deref()) {
+        request = request.header(TUNNEL_SECRET, secret);
+    }
+    if let Some(visitor_auth) = &registration.visitor_auth {
+        if !visitor_auth.basic.is_empty() {
+            request = request.header(VISITOR_BASIC_AUTH, hashes(&visitor_auth.basic));
+        }
+        if !visitor_auth.bearer.is_empty() {
+            request = request.header(VISITOR_BEARER_TOKENS, hashes(&visitor_auth.bearer));
+        }
+    }
+    let resp = request.send().await?;
+    if resp.status() == StatusCode::CONFLICT {
+        bail!("{} is in use by another tunnel", assigned_domain);
+    }
+    let resp = resp.json::<ProxyResponse>().await?;
     log::info!("Response from server: {:#?}", resp);
 
     let parts = resp.url.split("//").collect::<Vec<&str>>();
@@ -378,15 +683,55 @@ async fn get_tunnel_endpoint(
     };
 
     let tunnel_info = TunnelServerInfo {
+        id: resp.id,
         host: host.to_string(),
         port: resp.port,
         max_conn_count: resp.max_conn_count,
         url: resp.url,
+        secret: resp.secret,
+        handshake: resp.handshake,
+        multiplex: resp.transport.as_deref() == Some(MULTIPLEX_TRANSPORT),
     };
 
     Ok(tunnel_info)
 }
 
+/// Ask the server to close the tunnel, so its url is free at once instead of
+/// being kept until the server cleans it up.
+async fn close_tunnel(registration: &Registration, info: &TunnelServerInfo) {
+    let Some(secret) = &info.secret else {
+        return;
+    };
+    let server = registration.server.as_deref().unwrap_or(PROXY_SERVER);
+    let uri = format!("{}/api/tunnels/{}", server, info.id);
+
+    let result = reqwest::Client::new()
+        .delete(uri)
+        .header(TUNNEL_SECRET, secret)
+        .timeout(CLOSE_TIMEOUT)
+        .send()
+        .await;
+    match result {
+        Ok(resp) if resp.status().is_success() => log::info!("Closed tunnel {}", info.id),
+        Ok(resp) => log::warn!("Failed to close tunnel {}: {}", info.id, resp.status()),
+        Err(err) => log::warn!("Failed to close tunnel {}: {:?}", info.id, err),
+    }
+}
+
+/// Comma separated hex encoded sha256 of the values.
+fn hashes(values: &[String]) -> String {
+    values
+        .iter()
+        .map(|value| {
+            Sha256::digest(value.as_bytes())
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,86 @@
+use std::sync::{Arc, Mutex};
+
+use localtunnel_client::{broadcast, start_tunnel, ClientConfig};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::Duration;
+
+/// Registers `test` on the endpoint port and records every request head.
+async fn mock_api_server(
+    listener: TcpListener,
+    endpoint_port: u16,
+    requests: Arc<Mutex<Vec<String>>>,
+) {
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+        let mut buf = [0u8; 4096];
+        let len = stream.read(&mut buf).await.unwrap_or_default();
+        let request = String::from_utf8_lossy(&buf[..len]).to_lowercase();
+        requests.lock().unwrap().push(request.clone());
+
+        let response = if request.starts_with("delete") {
+            "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n".to_string()
+        } else {
+            let body = format!(
+                r#"{{"id":"test","port":{endpoint_port},"max_conn_count":1,"url":"http://test.127.0.0.1:{endpoint_port}","secret":"s3cret"}}"#,
+            );
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len(),
+            )
+        };
+        let _ = stream.write_all(response.as_bytes()).await;
+    }
+}
+
+#[tokio::test]
+async fn tunnel_is_closed_on_shutdown() {
+    let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_port = local.local_addr().unwrap().port();
+
+    let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let remote_port = remote.local_addr().unwrap().port();
+
+    let requests = Arc::new(Mutex::new(vec![]));
+    let api = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let api_port = api.local_addr().unwrap().port();
+    tokio::spawn(mock_api_server(api, remote_port, requests.clone()));
+
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let config = ClientConfig {
+        server: Some(format!("http://127.0.0.1:{api_port}")),
+        subdomain: Some("test".to_string()),
+        local_host: Some("127.0.0.1".to_string()),
+        local_port,
+        shutdown_signal: shutdown_tx.clone(),
+        max_conn: 1,
+        credential: None,
+        reregister_after: None,
+        multiplex: false,
+        tcp: false,
+        http2: false,
+        visitor_auth: None,
+        allow_ips: vec![],
+        deny_ips: vec![],
+        cleanup_timeout: None,
+        max_body_size: None,
+        upstream_timeout: None,
+        upgrade_idle_timeout: None,
+    };
+    let tunnel = start_tunnel(config).await.unwrap();
+    assert_eq!(tunnel.url, format!("http://test.127.0.0.1:{remote_port}"));
+
+    let _ = shutdown_tx.send(());
+    tokio::time::timeout(Duration::from_secs(5), tunnel.closed())
+        .await
+        .expect("tunnel should be closed after shutdown");
+
+    let requests = requests.lock().unwrap();
+    let close = requests.last().unwrap();
+    assert!(close.starts_with("delete /api/tunnels/test "));
+    assert!(close.contains("x-tunnel-secret: s3cret"));
+    drop((local, remote));
+}
@@ -80,6 +80,16 @@ async fn reregistration_on_remote_failure() {
         max_conn: 10,
         credential: None,
         reregister_after: Some(Duration::ZERO),
+        multiplex: false,
+        tcp: false,
+        http2: false,
+        visitor_auth: None,
+        allow_ips: vec![],
+        deny_ips: vec![],
+        cleanup_timeout: None,
+        max_body_size: None,
+        upstream_timeout: None,
+        upgrade_idle_timeout: None,
     };
     open_tunnel(config).await.unwrap();
 
@@ -0,0 +1,394 @@
+//! Access log of visitor requests, e.g. to debug webhook deliveries after the fact.
+//!
+//! Entries are written as JSON lines, or in the Common or Combined Log Format
+//! prefixed with the subdomain like Apache's `vhost_combined` and followed by the
+//! duration in milliseconds. A thread of its own writes them, the file is rotated
+//! to `<path>.1` ... `<path>.<max_files>` when it grows over `max_size`.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    str::FromStr,
+    sync::mpsc::{self, SyncSender, TrySendError},
+    task::{Context, Poll},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use bytes::Bytes;
+use hyper::{
+    body::{Body, Frame, SizeHint},
+    header::{HeaderName, REFERER, USER_AGENT},
+    Request,
+};
+use serde::Serialize;
+
+use crate::proxy::ProxyBody;
+
+/// Entries waiting for the writer, more are dropped.
+const QUEUE_LEN: usize = 4096;
+
+/// Defaults of the rotation.
+const DEFAULT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+const DEFAULT_MAX_FILES: usize = 5;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    #[default]
+    Json,
+    Common,
+    Combined,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(AccessLogFormat::Json),
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            _ => Err(format!(
+                "unknown access log format {s}, expected json, common or combined"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    pub path: PathBuf,
+    pub format: AccessLogFormat,
+    /// Bytes the file may grow to before it's rotated.
+    pub max_size: u64,
+    /// Rotated files kept besides the current one.
+    pub max_files: usize,
+}
+
+impl AccessLogConfig {
+    /// JSON lines rotated at 100 MiB, keeping 5 rotated files.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        AccessLogConfig {
+            path: path.into(),
+            format: AccessLogFormat::default(),
+            max_size: DEFAULT_MAX_SIZE,
+            max_files: DEFAULT_MAX_FILES,
+        }
+    }
+}
+
+/// A visitor request and its response.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessEntry {
+    #[serde(serialize_with = "rfc3339")]
+    pub time: SystemTime,
+    pub subdomain: Option<String>,
+    pub visitor: IpAddr,
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    pub status: u16,
+    /// bytes of the response body
+    pub bytes: u64,
+    #[serde(rename = "duration_ms", serialize_with = "millis")]
+    pub duration: Duration,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl AccessEntry {
+    pub fn new<B>(req: &Request<B>, visitor: IpAddr, subdomain: Option<String>) -> Self {
+        let header = |name: HeaderName| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        AccessEntry {
+            time: SystemTime::now(),
+            subdomain,
+            visitor,
+            method: req.method().to_string(),
+            path: req
+                .uri()
+                .path_and_query()
+                .map_or("/", |path| path.as_str())
+                .to_string(),
+            version: format!("{:?}", req.version()),
+            status: 0,
+            bytes: 0,
+            duration: Duration::ZERO,
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+        }
+    }
+
+    /// The line written to the log, without the newline.
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        if format == AccessLogFormat::Json {
+            return serde_json::to_string(self).unwrap_or_default();
+        }
+
+        let mut line = format!(
+            "{} {} - - [{}] \"{} {} {}\" {} {}",
+            self.subdomain.as_deref().unwrap_or("-"),
+            self.visitor,
+            clf_time(self.time),
+            self.method,
+            escape(&self.path),
+            self.version,
+            self.status,
+            self.bytes,
+        );
+        if format == AccessLogFormat::Combined {
+            line.push_str(&format!(
+                " \"{}\" \"{}\"",
+                escape(self.referer.as_deref().unwrap_or("-")),
+                escape(self.user_agent.as_deref().unwrap_or("-")),
+            ));
+        }
+        line.push_str(&format!(" {}", self.duration.as_millis()));
+        line
+    }
+}
+
+/// Queues entries for the writer thread, cheap to clone.
+#[derive(Clone)]
+pub struct AccessLog {
+    format: AccessLogFormat,
+    lines: SyncSender<String>,
+}
+
+impl AccessLog {
+    pub fn open(config: AccessLogConfig) -> io::Result<Self> {
+        let mut file = RotatingFile::open(&config)?;
+        let (lines, received) = mpsc::sync_channel::<String>(QUEUE_LEN);
+
+        thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                for line in received {
+                    if let Err(err) = file.write_line(&line) {
+                        log::error!("Failed to write the access log: {err}");
+                    }
+                }
+            })?;
+        log::info!("Write access log to {}", config.path.display());
+
+        Ok(AccessLog {
+            format: config.format,
+            lines,
+        })
+    }
+
+    pub fn write(&self, entry: &AccessEntry) {
+        match self.lines.try_send(entry.format(self.format)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => log::warn!("Access log is behind, dropped an entry"),
+            Err(TrySendError::Disconnected(_)) => log::error!("Access log writer has stopped"),
+        }
+    }
+}
+
+/// Response body counting the bytes sent, the entry is written once the body
+/// is done or dropped, e.g. when the visitor goes away.
+pub struct LoggedBody {
+    inner: ProxyBody,
+    log: AccessLog,
+    entry: AccessEntry,
+    started: Instant,
+}
+
+impl LoggedBody {
+    pub fn new(inner: ProxyBody, log: AccessLog, entry: AccessEntry, started: Instant) -> Self {
+        LoggedBody {
+            inner,
+            log,
+            entry,
+            started,
+        }
+    }
+}
+
+impl Body for LoggedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                this.entry.bytes += data.len() as u64;
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.entry.duration = self.started.elapsed();
+        self.log.write(&self.entry);
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(config: &AccessLogConfig) -> io::Result<Self> {
+        let file = append(&config.path)?;
+        Ok(RotatingFile {
+            path: config.path.clone(),
+            size: file.metadata()?.len(),
+            file,
+            max_size: config.max_size,
+            max_files: config.max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(format!("{line}\n").as_bytes())?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Shift the rotated files by one, dropping the oldest, and start a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files > 0 {
+            for i in (1..self.max_files).rev() {
+                match fs::rename(rotated(&self.path, i), rotated(&self.path, i + 1)) {
+                    Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                    _ => (),
+                }
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+
+        self.file = append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated(path: &Path, i: usize) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(format!(".{i}"));
+    PathBuf::from(path)
+}
+
+/// `18/Oct/2026:08:47:50 +0000`, rearranged from the HTTP date.
+fn clf_time(time: SystemTime) -> String {
+    let date = httpdate::fmt_http_date(time);
+    match date.split(' ').collect::<Vec<_>>()[..] {
+        [_, day, month, year, time, _] => format!("{day}/{month}/{year}:{time} +0000"),
+        _ => date,
+    }
+}
+
+/// Quotes and backslashes in a quoted CLF field.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn rfc3339<S: serde::Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&humantime::format_rfc3339_millis(*time))
+}
+
+fn millis<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u128(duration.as_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_entry_works() {
+        let req = Request::builder()
+            .uri("/hook?id=1")
+            .header(USER_AGENT, "curl \"8\"")
+            .body(())
+            .unwrap();
+        let mut entry = AccessEntry::new(&req, "203.0.113.7".parse().unwrap(), Some("demo".into()));
+        entry.time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        entry.status = 200;
+        entry.bytes = 12;
+        entry.duration = Duration::from_millis(34);
+
+        assert_eq!(
+            entry.format(AccessLogFormat::Common),
+            "demo 203.0.113.7 - - [14/Nov/2023:22:13:20 +0000] \"GET /hook?id=1 HTTP/1.1\" 200 12 34"
+        );
+        assert_eq!(
+            entry.format(AccessLogFormat::Combined),
+            "demo 203.0.113.7 - - [14/Nov/2023:22:13:20 +0000] \"GET /hook?id=1 HTTP/1.1\" 200 12 \"-\" \"curl \\\"8\\\"\" 34"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&entry.format(AccessLogFormat::Json)).unwrap();
+        assert_eq!(json["time"], "2023-11-14T22:13:20.000Z");
+        assert_eq!(json["subdomain"], "demo");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["duration_ms"], 34);
+    }
+
+    #[test]
+    fn file_is_rotated() {
+        let dir = std::env::temp_dir().join(format!("localtunnel-access-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let mut file = RotatingFile::open(&AccessLogConfig {
+            path: path.clone(),
+            format: AccessLogFormat::Json,
+            max_size: 10,
+            max_files: 2,
+        })
+        .unwrap();
+        for line in ["first", "second", "third", "fourth"] {
+            file.write_line(line).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(rotated(&path, 1)).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(rotated(&path, 2)).unwrap(), "second\n");
+        assert!(!rotated(&path, 3).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
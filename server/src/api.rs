@@ -1,30 +1,143 @@
-use actix_web::{get, web, HttpResponse, Responder};
+use actix_web::{
+    delete, get,
+    http::{header::AUTHORIZATION, StatusCode},
+    web, HttpRequest, HttpResponse, Responder,
+};
+use std::time::Duration;
+
 use anyhow::Result;
+use prometheus::TEXT_FORMAT;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-use crate::auth::{Auth, CfWorkerStore};
-use crate::state::State;
+use crate::auth::{constant_time_eq, file::hash, Grant};
+use crate::ip_filter::IpFilter;
+use crate::metrics::METRICS;
+use crate::state::{State, Transport, TunnelOptions, TunnelType, Upstream};
+use crate::stats::memory_usage;
+use crate::visitor_auth::{VisitorAuth, VISITOR_BASIC_AUTH, VISITOR_BEARER_TOKENS};
+
+/// Random endpoints tried before `/?new` gives up on a taken name.
+const RANDOM_ENDPOINT_ATTEMPTS: usize = 3;
+
+/// Header with the secret of the current client, so it can re-register its url
+/// without the credential that created it, or close the tunnel.
+pub const TUNNEL_SECRET: &str = "x-tunnel-secret";
 
 #[get("/api/status")]
-pub async fn api_status() -> impl Responder {
+pub async fn api_status(state: web::Data<State>) -> impl Responder {
+    let manager = state.manager.lock().await;
     let status = ApiStatus {
-        tunnels_count: 0,
-        tunels: "kaichao".to_string(),
+        tunnels_count: manager.clients.len(),
+        sockets_count: manager.sockets_count().await,
+        memory_usage: memory_usage(),
+        uptime: state.started_at.elapsed().as_secs(),
     };
 
     HttpResponse::Ok().json(status)
 }
 
+/// Prometheus metrics, only served if enabled in the server config.
+#[get("/metrics")]
+pub async fn server_metrics(state: web::Data<State>) -> impl Responder {
+    let manager = state.manager.lock().await;
+    HttpResponse::Ok()
+        .content_type(TEXT_FORMAT)
+        .body(METRICS.render(&manager).await)
+}
+
+#[get("/api/tunnels/{id}/status")]
+pub async fn tunnel_status(id: web::Path<String>, state: web::Data<State>) -> impl Responder {
+    let client = match state.manager.lock().await.clients.get(id.as_str()) {
+        Some(client) => client.clone(),
+        None => return HttpResponse::NotFound().body("Tunnel not found."),
+    };
+    let status = client.lock().await.status().await;
+
+    HttpResponse::Ok().json(TunnelStatus {
+        id: id.into_inner(),
+        connected_sockets: status.connected_sockets,
+        max_sockets: status.max_sockets,
+        port: status.port,
+        public_port: status.public_port,
+        last_connection_time: status.last_connection_time,
+        bytes_transferred: status.bytes_transferred,
+    })
+}
+
+/// Close the tunnel when its client shuts down, so the url is free at once.
+#[delete("/api/tunnels/{id}")]
+pub async fn close_tunnel(
+    req: HttpRequest,
+    id: web::Path<String>,
+    state: web::Data<State>,
+) -> impl Responder {
+    let mut manager = state.manager.lock().await;
+    let Some(client) = manager.clients.get(id.as_str()) else {
+        return HttpResponse::NotFound().body("Tunnel not found.");
+    };
+
+    let secret = req
+        .headers()
+        .get(TUNNEL_SECRET)
+        .and_then(|value| value.to_str().ok());
+    let authorized = match secret {
+        Some(secret) => constant_time_eq(client.lock().await.secret.as_bytes(), secret.as_bytes()),
+        None => false,
+    };
+    if !authorized {
+        return HttpResponse::Forbidden().body("Error: secret doesn't match the tunnel.");
+    }
+
+    manager.clients.remove(id.as_str());
+    log::info!("Closed tunnel {id}");
+    HttpResponse::NoContent().finish()
+}
+
+/// Request proxy endpoint with a random subdomain,
+/// compatible with the original localtunnel `GET /?new` request.
+#[get("/")]
+pub async fn request_random_endpoint(
+    req: HttpRequest,
+    new: web::Query<NewEndpointInfo>,
+    info: web::Query<AuthInfo>,
+    options: web::Query<RegisterOptions>,
+    state: web::Data<State>,
+) -> impl Responder {
+    if new.new.is_none() {
+        return HttpResponse::NotFound().body("Request a new proxy endpoint with `/?new`.");
+    }
+
+    let credential = match credential(&req, &info, state.reject_query_credential) {
+        Ok(credential) => credential,
+        Err(response) => return response,
+    };
+
+    // another request may take the same name before it's registered
+    let mut attempts = RANDOM_ENDPOINT_ATTEMPTS;
+    loop {
+        let endpoint = state.manager.lock().await.random_endpoint();
+        log::debug!("Request random proxy endpoint, {}", endpoint);
+
+        let response =
+            register_endpoint(endpoint, credential.clone(), &req, &options, &state, true).await;
+        attempts -= 1;
+        if response.status() != StatusCode::CONFLICT || attempts == 0 {
+            return response;
+        }
+    }
+}
+
 /// Request proxy endpoint
 #[get("/{endpoint}")]
 pub async fn request_endpoint(
+    req: HttpRequest,
     endpoint: web::Path<String>,
     info: web::Query<AuthInfo>,
+    options: web::Query<RegisterOptions>,
     state: web::Data<State>,
 ) -> impl Responder {
     log::debug!("Request proxy endpoint, {}", endpoint);
-    log::debug!("Require auth: {}", state.require_auth);
 
     match validate_endpoint(&endpoint) {
         Ok(true) => (),
@@ -38,22 +151,74 @@ pub async fn request_endpoint(
         }
     }
 
+    let credential = match credential(&req, &info, state.reject_query_credential) {
+        Ok(credential) => credential,
+        Err(response) => return response,
+    };
+
+    register_endpoint(
+        endpoint.into_inner(),
+        credential,
+        &req,
+        &options,
+        &state,
+        false,
+    )
+    .await
+}
+
+/// The credential from the `Authorization: Bearer` header, falling back to
+/// the `credential` query param of older clients unless it's refused.
+fn credential(
+    req: &HttpRequest,
+    info: &AuthInfo,
+    reject_query: bool,
+) -> Result<Option<String>, HttpResponse> {
+    let bearer = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, credential)| credential.trim().to_string());
+
+    match (bearer, &info.credential) {
+        (Some(credential), _) => Ok(Some(credential)),
+        (None, Some(_)) if reject_query => Err(HttpResponse::BadRequest()
+            .body("Request Error: send the credential in the Authorization header.")),
+        (None, query) => Ok(query.clone()),
+    }
+}
+
+/// Register the tunnel, a `random` endpoint must not be in use at all.
+async fn register_endpoint(
+    endpoint: String,
+    credential: Option<String>,
+    req: &HttpRequest,
+    options: &RegisterOptions,
+    state: &State,
+    random: bool,
+) -> HttpResponse {
+    log::debug!("Require auth: {}", state.require_auth);
+    let owner = credential.as_deref().map(hash);
+
+    let mut grant = Grant::default();
     if state.require_auth {
-        let credential = match info.credential.clone() {
+        let credential = match credential {
             Some(val) => val,
             None => {
-                return HttpResponse::BadRequest().body("Request Error: credential param is empty.")
+                METRICS.auth_failures.inc();
+                return HttpResponse::BadRequest()
+                    .body("Request Error: credential param is empty.");
             }
         };
 
-        match CfWorkerStore
-            .credential_is_valid(&credential, &endpoint)
-            .await
-        {
-            Ok(true) => (),
-            Ok(false) => {
+        match state.auth.authorize(&credential, &endpoint).await {
+            Ok(Some(granted)) => grant = granted,
+            Ok(None) => {
+                METRICS.auth_failures.inc();
                 return HttpResponse::BadRequest()
-                    .body("Error: credential is not valid.".to_string())
+                    .body("Error: credential is not valid.".to_string());
             }
             Err(err) => {
                 log::error!("Server error: {:?}", err);
@@ -63,18 +228,93 @@ pub async fn request_endpoint(
         };
     }
 
+    // fallback to sockets if the server doesn't accept multiplexed connections
+    let transport = match (options.transport, state.mux_port) {
+        (Some(Transport::Multiplex), Some(_)) => Transport::Multiplex,
+        _ => Transport::Sockets,
+    };
+
+    let tunnel_type = options.tunnel_type.unwrap_or_default();
+    if grant
+        .tunnel_type
+        .is_some_and(|allowed| allowed != tunnel_type)
+    {
+        METRICS.auth_failures.inc();
+        return HttpResponse::BadRequest()
+            .body("Error: credential doesn't allow this tunnel type.");
+    }
+
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let visitor_auth = match VisitorAuth::from_headers(
+        header(VISITOR_BASIC_AUTH),
+        header(VISITOR_BEARER_TOKENS),
+    ) {
+        Ok(visitor_auth) => visitor_auth,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Request Error: {err}")),
+    };
+
+    let ip_filter = match IpFilter::parse(options.allow.as_deref(), options.deny.as_deref()) {
+        Ok(ip_filter) => ip_filter,
+        Err(err) => return HttpResponse::BadRequest().body(format!("Request Error: {err}")),
+    };
+
+    let tunnel_options = TunnelOptions {
+        transport,
+        tunnel_type,
+        upstream: options.upstream.unwrap_or_default(),
+        max_sockets: grant.max_sockets,
+        visitor_auth,
+        ip_filter,
+        owner: owner.clone(),
+        cleanup_timeout: options.cleanup_timeout.map(Duration::from_secs),
+        handshake: options.handshake.unwrap_or_default(),
+        max_body_size: options.max_body_size,
+        upstream_timeout: options.upstream_timeout.map(Duration::from_secs),
+        upgrade_idle_timeout: options.upgrade_idle_timeout.map(Duration::from_secs),
+    };
     let mut manager = state.manager.lock().await;
-    match manager.put(endpoint.to_string()).await {
-        Ok(port) => {
-            let schema = if state.secure { "https" } else { "http" };
+    // checked under the same lock the client is put with
+    if random && manager.clients.contains_key(&endpoint) {
+        return HttpResponse::Conflict().body("Error: subdomain is in use by another tunnel.");
+    }
+    if !manager
+        .can_claim(&endpoint, owner.as_deref(), header(TUNNEL_SECRET))
+        .await
+    {
+        log::info!("Refused to register {endpoint}, it's owned by another client");
+        return HttpResponse::Conflict().body("Error: subdomain is in use by another tunnel.");
+    }
+    match manager.put(endpoint.clone(), tunnel_options).await {
+        Ok(registration) => {
+            let url = match registration.public_port {
+                Some(public_port) => format!("tcp://{}.{}:{}", endpoint, state.domain, public_port),
+                None => {
+                    let schema = if state.secure { "https" } else { "http" };
+                    format!("{}://{}.{}", schema, endpoint, state.domain)
+                }
+            };
             let info = ProxyInfo {
-                id: endpoint.to_string(),
-                port,
-                max_conn_count: state.max_sockets,
-                url: format!("{}://{}.{}", schema, endpoint, state.domain),
+                id: endpoint.clone(),
+                port: registration.port.or(state.mux_port).unwrap_or_default(),
+                transport,
+                tunnel_type,
+                public_port: registration.public_port,
+                max_conn_count: registration.max_sockets,
+                cleanup_timeout: registration.cleanup_timeout.as_secs(),
+                max_body_size: registration.limits.max_body_size,
+                upstream_timeout: registration.limits.upstream_timeout.as_secs(),
+                upgrade_idle_timeout: registration.limits.upgrade_idle_timeout.as_secs(),
+                url,
+                secret: registration.secret,
+                handshake: options.handshake.unwrap_or_default(),
             };
 
             log::debug!("Proxy info, {:?}", info);
+            METRICS.registrations.inc();
             HttpResponse::Ok().json(info)
         }
         Err(e) => {
@@ -95,23 +335,94 @@ pub struct AuthInfo {
     credential: Option<String>,
 }
 
+/// Query of `/?new`, the flag carries no value in the original localtunnel client.
+#[derive(Debug, Deserialize)]
+pub struct NewEndpointInfo {
+    new: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RegisterOptions {
+    transport: Option<Transport>,
+    /// `tcp` to expose the tunnel on a public port of its own
+    #[serde(rename = "type")]
+    tunnel_type: Option<TunnelType>,
+    /// `h2` if the local service speaks HTTP/2 without TLS
+    upstream: Option<Upstream>,
+    /// comma separated CIDRs or addresses of the only visitors allowed
+    allow: Option<String>,
+    /// comma separated CIDRs or addresses of visitors refused
+    deny: Option<String>,
+    /// seconds the tunnel is kept unused, capped by the server's maximum
+    cleanup_timeout: Option<u64>,
+    /// sockets start with the secret, not sent by the upstream Node client
+    handshake: Option<bool>,
+    /// limits of visitor requests in bytes and seconds, capped by the server's
+    max_body_size: Option<u64>,
+    upstream_timeout: Option<u64>,
+    upgrade_idle_timeout: Option<u64>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ApiStatus {
-    tunnels_count: u16,
-    tunels: String,
+    tunnels_count: usize,
+    sockets_count: usize,
+    /// resident memory in bytes
+    memory_usage: Option<u64>,
+    /// uptime in seconds
+    uptime: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TunnelStatus {
+    id: String,
+    connected_sockets: usize,
+    max_sockets: u8,
+    port: Option<u16>,
+    public_port: Option<u16>,
+    /// unix timestamp in seconds
+    last_connection_time: u64,
+    bytes_transferred: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ProxyInfo {
     id: String,
+    /// the mux port for multiplexed clients
     port: u16,
+    transport: Transport,
+    #[serde(rename = "type")]
+    tunnel_type: TunnelType,
+    /// the port visitors connect to for tcp tunnels
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_port: Option<u16>,
     max_conn_count: u8,
+    /// seconds the tunnel is kept unused before it's cleaned up
+    cleanup_timeout: u64,
+    /// bytes of a visitor request body
+    max_body_size: u64,
+    /// seconds to wait for the response headers from the client
+    upstream_timeout: u64,
+    /// seconds an upgraded connection may be idle
+    upgrade_idle_timeout: u64,
     url: String,
+    /// proves the client when it re-registers or closes the tunnel
+    secret: String,
+    /// client must send the secret followed by a newline on each socket before it's used
+    handshake: bool,
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::api::validate_endpoint;
+    use actix_web::test::TestRequest;
+
+    use std::{path::Path, sync::Arc, time::Instant};
+
+    use tokio::sync::Mutex;
+
+    use super::*;
+    use crate::auth::FileStore;
+    use crate::state::ClientManager;
 
     #[test]
     fn validate_endpoint_works() {
@@ -125,4 +436,57 @@ mod tests {
             assert!(validate_endpoint(endpoint).unwrap());
         }
     }
+
+    #[test]
+    fn credential_prefers_authorization_header() {
+        let info = AuthInfo {
+            credential: Some("query".to_string()),
+        };
+        let req = TestRequest::default()
+            .insert_header((AUTHORIZATION, "Bearer a&b+c"))
+            .to_http_request();
+        assert_eq!(
+            credential(&req, &info, true).unwrap(),
+            Some("a&b+c".to_string())
+        );
+
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(
+            credential(&req, &info, false).unwrap(),
+            Some("query".to_string())
+        );
+        assert!(credential(&req, &info, true).is_err());
+    }
+
+    #[actix_web::test]
+    async fn random_endpoint_is_registered_once() {
+        let state = State {
+            manager: Arc::new(Mutex::new(ClientManager::new(10))),
+            require_auth: false,
+            auth: Box::new(FileStore::open(Path::new("missing-credentials.json")).unwrap()),
+            reject_query_credential: false,
+            secure: false,
+            domain: "localhost".to_string(),
+            mux_port: None,
+            started_at: Instant::now(),
+        };
+        let req = TestRequest::default().to_http_request();
+        let options = RegisterOptions::default();
+        let register =
+            |random| register_endpoint("demo".to_string(), None, &req, &options, &state, random);
+
+        assert_eq!(register(true).await.status(), StatusCode::OK);
+        assert_eq!(register(true).await.status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn random_endpoint_is_valid() {
+        let manager = ClientManager::new(10);
+
+        for _ in 0..100 {
+            let endpoint = manager.random_endpoint();
+            assert!(validate_endpoint(&endpoint).unwrap());
+            assert!(!manager.clients.contains_key(&endpoint));
+        }
+    }
 }
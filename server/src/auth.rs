@@ -1,14 +1,45 @@
+//! Validation of the credentials tunnel clients register with.
+//!
+//! Implement [`Auth`] to plug in another credential store via `ServerConfig::auth`.
+
 use anyhow::Result;
 use async_trait::async_trait;
 
-use crate::error::ServerError;
-use crate::CONFIG;
+use crate::state::TunnelType;
+
+pub mod cloudflare;
+pub mod file;
+pub mod token;
+
+pub use cloudflare::CfWorkerStore;
+pub use file::FileStore;
+pub use token::TokenAuth;
 
 #[async_trait]
 pub trait Auth {
+    /// If the credential allows registering the endpoint `value`.
     async fn credential_is_valid(&self, credential: &str, value: &str) -> Result<bool>;
+
+    /// What the credential allows for the endpoint, `None` if it's not valid.
+    /// Credentials only checked for validity grant what the server allows.
+    async fn authorize(&self, credential: &str, value: &str) -> Result<Option<Grant>> {
+        Ok(self
+            .credential_is_valid(credential, value)
+            .await?
+            .then(Grant::default))
+    }
+}
+
+/// Limits a credential puts on the tunnel it registers.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Grant {
+    /// Sockets the tunnel may open, capped by the server's maximum.
+    pub max_sockets: Option<u8>,
+    /// The only tunnel type allowed, any if `None`.
+    pub tunnel_type: Option<TunnelType>,
 }
 
+/// Accepts any credential.
 #[async_trait]
 impl Auth for () {
     async fn credential_is_valid(&self, _credential: &str, _value: &str) -> Result<bool> {
@@ -16,42 +47,50 @@ impl Auth for () {
     }
 }
 
-pub struct CfWorkerStore;
+/// Match a subdomain against a pattern where `*` matches any chars, e.g. `dev-*`.
+pub fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut value) = value.strip_prefix(first) else {
+        return false;
+    };
 
-#[async_trait]
-impl Auth for CfWorkerStore {
-    async fn credential_is_valid(&self, credential: &str, value: &str) -> Result<bool> {
-        let account = CONFIG
-            .cloudflare_account
-            .clone()
-            .ok_or(ServerError::InvalidConfig)?;
-        let namespace = CONFIG
-            .cloudflare_namespace
-            .clone()
-            .ok_or(ServerError::InvalidConfig)?;
-        let email = CONFIG
-            .cloudflare_auth_email
-            .clone()
-            .ok_or(ServerError::InvalidConfig)?;
-        let key = CONFIG
-            .cloudflare_auth_key
-            .clone()
-            .ok_or(ServerError::InvalidConfig)?;
-
-        let client = reqwest::Client::new();
-        let resp = client.get(
-            format!(
-                "https://api.cloudflare.com/client/v4/accounts/{}/storage/kv/namespaces/{}/values/{}",
-                account, namespace, value
-            ))
-            .header("X-Auth-Email", email)
-            .header("X-Auth-Key", key)
-            .send()
-            .await?
-            .text()
-            .await?;
-        log::info!("{:#?}", resp);
+    let rest = parts.collect::<Vec<_>>();
+    let Some((last, middle)) = rest.split_last() else {
+        // no wildcard
+        return value.is_empty();
+    };
+    for part in middle {
+        match value.find(part) {
+            Some(index) => value = &value[index + part.len()..],
+            None => return false,
+        }
+    }
+    value.ends_with(last)
+}
+
+/// Compare two byte slices in constant time to avoid leaking secrets via timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(credential == resp)
+    #[test]
+    fn wildcard_match_works() {
+        assert!(wildcard_match("demo", "demo"));
+        assert!(wildcard_match("*", "demo"));
+        assert!(wildcard_match("dev-*", "dev-kai"));
+        assert!(wildcard_match("*-api", "kai-api"));
+        assert!(wildcard_match("dev-*-api", "dev-kai-api"));
+        assert!(!wildcard_match("demo", "demo2"));
+        assert!(!wildcard_match("dev-*", "prod-kai"));
+        assert!(!wildcard_match("dev-*-api", "dev-api"));
     }
 }
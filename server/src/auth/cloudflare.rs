@@ -0,0 +1,200 @@
+//! Credentials stored in Cloudflare KV with the endpoint as key.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::StatusCode;
+
+use super::{constant_time_eq, Auth};
+use crate::error::ServerError;
+use crate::CONFIG;
+
+const DEFAULT_API_URL: &str = "https://api.cloudflare.com/client/v4";
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a stored credential is used before it's fetched again.
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+/// Missing keys are cached shortly, so new credentials work soon.
+const NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// Configured by the `CLOUDFLARE_*` environment variables,
+/// `CLOUDFLARE_API_URL` overrides the API base url, e.g. for a mock.
+pub struct CfWorkerStore {
+    client: reqwest::Client,
+    api_url: String,
+    /// The credential stored for each endpoint, `None` if there is no key.
+    cache: Mutex<HashMap<String, (Option<String>, Instant)>>,
+}
+
+impl CfWorkerStore {
+    pub fn new() -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        Ok(CfWorkerStore {
+            client,
+            api_url: CONFIG
+                .cloudflare_api_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_API_URL.to_string()),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn with_api_url(mut self, api_url: impl Into<String>) -> Self {
+        self.api_url = api_url.into();
+        self
+    }
+
+    fn cached(&self, value: &str) -> Option<Option<String>> {
+        let cache = self.cache.lock().unwrap();
+        let (stored, fetched_at) = cache.get(value)?;
+        let ttl = if stored.is_some() {
+            CACHE_TTL
+        } else {
+            NEGATIVE_CACHE_TTL
+        };
+        (fetched_at.elapsed() < ttl).then(|| stored.clone())
+    }
+
+    fn cache(&self, value: &str, stored: Option<String>) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (_, fetched_at)| fetched_at.elapsed() < CACHE_TTL);
+        cache.insert(value.to_string(), (stored, Instant::now()));
+    }
+
+    /// The credential stored for the endpoint, `None` if there is no key.
+    async fn fetch(&self, value: &str) -> Result<Option<String>> {
+        let account = CONFIG
+            .cloudflare_account
+            .as_deref()
+            .ok_or(ServerError::InvalidConfig)?;
+        let namespace = CONFIG
+            .cloudflare_namespace
+            .as_deref()
+            .ok_or(ServerError::InvalidConfig)?;
+        let email = CONFIG
+            .cloudflare_auth_email
+            .as_deref()
+            .ok_or(ServerError::InvalidConfig)?;
+        let key = CONFIG
+            .cloudflare_auth_key
+            .as_deref()
+            .ok_or(ServerError::InvalidConfig)?;
+
+        let resp = self
+            .client
+            .get(format!(
+                "{}/accounts/{}/storage/kv/namespaces/{}/values/{}",
+                self.api_url, account, namespace, value
+            ))
+            .header("X-Auth-Email", email)
+            .header("X-Auth-Key", key)
+            .send()
+            .await?;
+
+        match resp.status() {
+            StatusCode::OK => Ok(Some(resp.text().await?)),
+            StatusCode::NOT_FOUND => Ok(None),
+            status => Err(anyhow!("Cloudflare KV responded {status}")),
+        }
+    }
+}
+
+#[async_trait]
+impl Auth for CfWorkerStore {
+    async fn credential_is_valid(&self, credential: &str, value: &str) -> Result<bool> {
+        let stored = match self.cached(value) {
+            Some(stored) => stored,
+            None => {
+                let stored = self.fetch(value).await?;
+                self.cache(value, stored.clone());
+                stored
+            }
+        };
+        log::debug!("Credential of {value} is stored: {}", stored.is_some());
+
+        Ok(stored.is_some_and(|stored| constant_time_eq(stored.as_bytes(), credential.as_bytes())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Serves `demo` as a stored key, `broken` as a failing one and no others,
+    /// returns the base url and the count of requests.
+    async fn mock_kv() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let count = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                count.fetch_add(1, Ordering::SeqCst);
+
+                let mut buf = [0u8; 4096];
+                let len = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..len]);
+                let (status, body) = if request.contains("/values/demo ") {
+                    ("200 OK", "secret")
+                } else if request.contains("/values/broken ") {
+                    ("500 Internal Server Error", "error")
+                } else {
+                    ("404 Not Found", "{\"success\":false}")
+                };
+                let response = format!(
+                    "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        (format!("http://{addr}"), requests)
+    }
+
+    #[tokio::test]
+    async fn credential_lookup_is_cached() {
+        std::env::set_var("CLOUDFLARE_ACCOUNT", "account");
+        std::env::set_var("CLOUDFLARE_NAMESPACE", "namespace");
+        std::env::set_var("CLOUDFLARE_AUTH_EMAIL", "email");
+        std::env::set_var("CLOUDFLARE_AUTH_KEY", "key");
+
+        let (api_url, requests) = mock_kv().await;
+        let store = CfWorkerStore::new().unwrap().with_api_url(api_url);
+
+        assert!(store.credential_is_valid("secret", "demo").await.unwrap());
+        assert!(!store.credential_is_valid("wrong", "demo").await.unwrap());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // a missing key is not valid, not compared with the error body
+        assert!(!store
+            .credential_is_valid("{\"success\":false}", "other")
+            .await
+            .unwrap());
+        assert!(!store.credential_is_valid("secret", "other").await.unwrap());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        assert!(store.credential_is_valid("error", "broken").await.is_err());
+    }
+}
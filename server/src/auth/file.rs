@@ -0,0 +1,248 @@
+//! Credentials stored in a local JSON file, for servers which can't reach Cloudflare.
+//!
+//! The file maps subdomains to the hex encoded sha256 of their credential,
+//! a `*` in the subdomain matches any chars, e.g. `dev-*` or `*`:
+//!
+//! ```json
+//! {
+//!   "demo": "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b",
+//!   "dev-*": "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8"
+//! }
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+
+use super::{constant_time_eq, wildcard_match, Auth};
+
+/// Length of the credentials generated by `add` and `rotate`.
+const CREDENTIAL_LEN: usize = 32;
+
+/// Subdomain patterns and the sha256 of their credential.
+pub type Credentials = BTreeMap<String, String>;
+
+/// Checks credentials against the file, reloaded when it changes.
+pub struct FileStore {
+    path: PathBuf,
+    cache: RwLock<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    modified: Option<SystemTime>,
+    credentials: Credentials,
+}
+
+impl FileStore {
+    /// Load the credentials, a missing file has none until it's created.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let store = FileStore {
+            path: path.to_path_buf(),
+            cache: RwLock::new(Cache {
+                modified: modified(fs::metadata(path))?,
+                credentials: read(path)?,
+            }),
+        };
+        log::info!(
+            "Loaded {} credentials from {}",
+            store.cache.read().unwrap().credentials.len(),
+            path.display()
+        );
+
+        Ok(store)
+    }
+
+    async fn reload_if_changed(&self) -> io::Result<()> {
+        let modified = modified(tokio::fs::metadata(&self.path).await)?;
+        if modified.is_some() && modified == self.cache.read().unwrap().modified {
+            return Ok(());
+        }
+
+        let credentials = parse(tokio::fs::read(&self.path).await)?;
+        *self.cache.write().unwrap() = Cache {
+            modified,
+            credentials,
+        };
+        log::debug!("Reloaded credentials from {}", self.path.display());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Auth for FileStore {
+    async fn credential_is_valid(&self, credential: &str, value: &str) -> Result<bool> {
+        // a broken file keeps the credentials loaded before
+        if let Err(err) = self.reload_if_changed().await {
+            log::error!("Failed to reload {}: {err}", self.path.display());
+        }
+
+        let cache = self.cache.read().unwrap();
+        Ok(is_valid(&cache.credentials, credential, value))
+    }
+}
+
+/// An exact entry of the subdomain takes precedence over the patterns.
+fn is_valid(credentials: &Credentials, credential: &str, subdomain: &str) -> bool {
+    let hash = hash(credential);
+    let matches = |expected: &String| constant_time_eq(expected.as_bytes(), hash.as_bytes());
+
+    match credentials.get(subdomain) {
+        Some(expected) => matches(expected),
+        None => credentials
+            .iter()
+            .filter(|(pattern, _)| pattern.contains('*') && wildcard_match(pattern, subdomain))
+            .any(|(_, expected)| matches(expected)),
+    }
+}
+
+/// Hex encoded sha256 of the credential, as stored in the file.
+pub fn hash(credential: &str) -> String {
+    Sha256::digest(credential.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Read the credentials file, a missing file has none.
+pub fn read(path: &Path) -> io::Result<Credentials> {
+    parse(fs::read(path))
+}
+
+fn parse(content: io::Result<Vec<u8>>) -> io::Result<Credentials> {
+    match content {
+        Ok(content) => serde_json::from_slice(&content)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(Credentials::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Modification time of the file, `None` if it's missing or unknown.
+fn modified(metadata: io::Result<fs::Metadata>) -> io::Result<Option<SystemTime>> {
+    match metadata {
+        Ok(metadata) => Ok(metadata.modified().ok()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Replace the file at once, so a running server never reads half of it.
+pub fn write(path: &Path, credentials: &Credentials) -> io::Result<()> {
+    let content = serde_json::to_vec_pretty(credentials)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(tmp, path)
+}
+
+/// Add a credential for the subdomain pattern, returns the generated credential.
+pub fn add(path: &Path, pattern: &str) -> io::Result<String> {
+    let mut credentials = read(path)?;
+    if credentials.contains_key(pattern) {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{pattern} already has a credential, rotate it instead"),
+        ));
+    }
+
+    let credential = generate();
+    credentials.insert(pattern.to_string(), hash(&credential));
+    write(path, &credentials)?;
+    Ok(credential)
+}
+
+/// Remove the credential of the subdomain pattern.
+pub fn remove(path: &Path, pattern: &str) -> io::Result<()> {
+    let mut credentials = read(path)?;
+    if credentials.remove(pattern).is_none() {
+        return Err(not_found(pattern));
+    }
+    write(path, &credentials)
+}
+
+/// Replace the credential of the subdomain pattern, returns the new credential.
+pub fn rotate(path: &Path, pattern: &str) -> io::Result<String> {
+    let mut credentials = read(path)?;
+    let credential = generate();
+    match credentials.get_mut(pattern) {
+        Some(expected) => *expected = hash(&credential),
+        None => return Err(not_found(pattern)),
+    }
+    write(path, &credentials)?;
+    Ok(credential)
+}
+
+fn generate() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(CREDENTIAL_LEN)
+        .map(char::from)
+        .collect()
+}
+
+fn not_found(pattern: &str) -> io::Error {
+    io::Error::new(ErrorKind::NotFound, format!("{pattern} has no credential"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn file_store_reloads_changed_credentials() {
+        let path = std::env::temp_dir().join(format!(
+            "localtunnel-credentials-{}.json",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        let demo = add(&path, "demo").unwrap();
+        let dev = add(&path, "dev-*").unwrap();
+        assert!(add(&path, "demo").is_err());
+
+        let store = FileStore::open(&path).unwrap();
+        assert!(store.credential_is_valid(&demo, "demo").await.unwrap());
+        assert!(store.credential_is_valid(&dev, "dev-kai").await.unwrap());
+        // the exact entry wins over the pattern
+        assert!(!store.credential_is_valid(&dev, "demo").await.unwrap());
+        assert!(!store.credential_is_valid(&demo, "other").await.unwrap());
+
+        let rotated = rotate(&path, "demo").unwrap();
+        remove(&path, "dev-*").unwrap();
+        // the mtime may not have changed within the same tick
+        store.cache.write().unwrap().modified = None;
+        assert!(store.credential_is_valid(&rotated, "demo").await.unwrap());
+        assert!(!store.credential_is_valid(&demo, "demo").await.unwrap());
+        assert!(!store.credential_is_valid(&dev, "dev-kai").await.unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn file_store_starts_without_the_file() {
+        let path = std::env::temp_dir().join(format!(
+            "localtunnel-credentials-missing-{}.json",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+
+        let store = FileStore::open(&path).unwrap();
+        assert!(!store.credential_is_valid("secret", "demo").await.unwrap());
+
+        let demo = add(&path, "demo").unwrap();
+        assert!(store.credential_is_valid(&demo, "demo").await.unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
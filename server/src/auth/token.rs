@@ -0,0 +1,144 @@
+//! Stateless tunnel tokens signed with a secret shared by the server and the issuer,
+//! so short-lived credentials can be minted, e.g. in CI, without a credential store.
+//!
+//! A token is `<payload>.<signature>`, the base64url encoded json [`Claims`] and
+//! their HMAC-SHA256.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use super::{wildcard_match, Auth, Grant};
+use crate::state::TunnelType;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a token allows.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    /// Subdomain pattern, `*` matches any chars, e.g. `ci-*`.
+    pub sub: String,
+    /// Unix timestamp in seconds the token expires at.
+    pub exp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sockets: Option<u8>,
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub tunnel_type: Option<TunnelType>,
+}
+
+/// Validates the tokens signed with the secret.
+pub struct TokenAuth {
+    secret: Vec<u8>,
+}
+
+impl TokenAuth {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        TokenAuth {
+            secret: secret.into(),
+        }
+    }
+
+    /// Sign the claims into a token.
+    pub fn issue(&self, claims: &Claims) -> Result<String> {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims)?);
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes());
+        Ok(format!("{payload}.{signature}"))
+    }
+
+    /// The claims of a token with a valid signature which hasn't expired.
+    pub fn verify(&self, token: &str) -> Option<Claims> {
+        let (payload, signature) = token.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        self.mac(payload).verify_slice(&signature).ok()?;
+
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+        (claims.exp > now()).then_some(claims)
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("hmac accepts keys of any length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+#[async_trait]
+impl Auth for TokenAuth {
+    async fn credential_is_valid(&self, credential: &str, value: &str) -> Result<bool> {
+        Ok(self.authorize(credential, value).await?.is_some())
+    }
+
+    async fn authorize(&self, credential: &str, value: &str) -> Result<Option<Grant>> {
+        let Some(claims) = self.verify(credential) else {
+            log::debug!("Token is invalid or expired");
+            return Ok(None);
+        };
+        if !wildcard_match(&claims.sub, value) {
+            log::debug!("Token for {} doesn't allow {value}", claims.sub);
+            return Ok(None);
+        }
+
+        Ok(Some(Grant {
+            max_sockets: claims.max_sockets,
+            tunnel_type: claims.tunnel_type,
+        }))
+    }
+}
+
+/// Unix timestamp in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn authorize_token_works() {
+        let auth = TokenAuth::new("secret");
+        let token = auth
+            .issue(&Claims {
+                sub: "ci-*".to_string(),
+                exp: now() + 60,
+                max_sockets: Some(2),
+                tunnel_type: Some(TunnelType::Tcp),
+            })
+            .unwrap();
+
+        assert_eq!(
+            auth.authorize(&token, "ci-123").await.unwrap(),
+            Some(Grant {
+                max_sockets: Some(2),
+                tunnel_type: Some(TunnelType::Tcp),
+            })
+        );
+        assert_eq!(auth.authorize(&token, "demo").await.unwrap(), None);
+
+        let other = TokenAuth::new("other");
+        assert!(!other.credential_is_valid(&token, "ci-123").await.unwrap());
+
+        let (payload, signature) = token.split_once('.').unwrap();
+        let forged = format!("{payload}x.{signature}");
+        assert!(!auth.credential_is_valid(&forged, "ci-123").await.unwrap());
+
+        let expired = auth
+            .issue(&Claims {
+                sub: "*".to_string(),
+                exp: now() - 1,
+                max_sockets: None,
+                tunnel_type: None,
+            })
+            .unwrap();
+        assert!(!auth.credential_is_valid(&expired, "demo").await.unwrap());
+    }
+}
@@ -6,4 +6,5 @@ pub struct Config {
     pub cloudflare_namespace: Option<String>,
     pub cloudflare_auth_email: Option<String>,
     pub cloudflare_auth_key: Option<String>,
+    pub cloudflare_api_url: Option<String>,
 }
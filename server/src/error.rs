@@ -1,3 +1,4 @@
+use hyper::StatusCode;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -6,14 +7,43 @@ pub enum ServerError {
     NoHostHeader,
     #[error("Proxy connection is not setup")]
     ProxyNotReady,
+    #[error("Visitor is not authorized")]
+    Unauthorized { challenge: String },
+    #[error("Visitor address is not allowed")]
+    Forbidden,
     #[error("Client connection is empty")]
     EmptyConnection,
+    #[error("No client connection is available in time")]
+    NoConnectionAvailable,
+    #[error("Request body is too large")]
+    PayloadTooLarge,
+    #[error("Client did not respond in time")]
+    UpstreamTimeout,
     #[error("Must contain an upgrade extension")]
     NoUpgradeExtension,
-    #[error("Must contain host header")]
+    #[error("Must contain upgrade header")]
     NoUpgradeHeader,
     #[error("Host name is invalid")]
     InvalidHostName,
     #[error("Server config is not valid")]
     InvalidConfig,
 }
+
+impl ServerError {
+    /// Status code of the response to visitors.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ServerError::NoHostHeader | ServerError::InvalidHostName => StatusCode::BAD_REQUEST,
+            ServerError::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            ServerError::Forbidden => StatusCode::FORBIDDEN,
+            ServerError::ProxyNotReady => StatusCode::NOT_FOUND,
+            ServerError::EmptyConnection
+            | ServerError::NoUpgradeExtension
+            | ServerError::NoUpgradeHeader => StatusCode::BAD_GATEWAY,
+            ServerError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ServerError::NoConnectionAvailable => StatusCode::SERVICE_UNAVAILABLE,
+            ServerError::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+            ServerError::InvalidConfig => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
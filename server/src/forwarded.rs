@@ -0,0 +1,163 @@
+//! `X-Forwarded-*` and RFC 7239 `Forwarded` headers, so the local server
+//! behind the tunnel learns the visitor's address and scheme.
+
+use std::net::IpAddr;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, FORWARDED};
+
+pub const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+pub const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+pub const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// Add the forwarded headers for a request from `peer` to `host`, passed in as
+/// HTTP/2 visitors send the `:authority` instead of a `Host` header.
+///
+/// If `trust_upstream` is set, the server runs behind another proxy (e.g. Caddy),
+/// values it already set are kept and the peer is appended to the chain.
+/// Otherwise values sent by the visitor are spoofable and get replaced.
+pub fn set_forwarded_headers(
+    headers: &mut HeaderMap,
+    peer: IpAddr,
+    proto: &str,
+    host: &str,
+    trust_upstream: bool,
+) {
+    let forwarded_for = match existing(headers, &X_FORWARDED_FOR, trust_upstream) {
+        Some(chain) => format!("{chain}, {peer}"),
+        None => peer.to_string(),
+    };
+    insert(headers, X_FORWARDED_FOR, &forwarded_for);
+
+    if existing(headers, &X_FORWARDED_PROTO, trust_upstream).is_none() {
+        insert(headers, X_FORWARDED_PROTO, proto);
+    }
+
+    if existing(headers, &X_FORWARDED_HOST, trust_upstream).is_none() {
+        insert(headers, X_FORWARDED_HOST, host);
+    }
+
+    let element = format!(
+        "for={};proto={proto};host=\"{}\"",
+        forwarded_node(peer),
+        host.replace('"', "\\\"")
+    );
+    let forwarded = match existing(headers, &FORWARDED, trust_upstream) {
+        Some(forwarded) => format!("{forwarded}, {element}"),
+        None => element,
+    };
+    insert(headers, FORWARDED, &forwarded);
+}
+
+/// The visitor address, the one appended to `X-Forwarded-For` by a trusted
+/// proxy in front of the server, or the peer otherwise.
+pub fn client_addr(headers: &HeaderMap, peer: IpAddr, trust_upstream: bool) -> IpAddr {
+    existing(headers, &X_FORWARDED_FOR, trust_upstream)
+        .and_then(|chain| chain.rsplit(',').next()?.trim().parse().ok())
+        .unwrap_or(peer)
+}
+
+fn existing(headers: &HeaderMap, name: &HeaderName, trust_upstream: bool) -> Option<String> {
+    if !trust_upstream {
+        return None;
+    }
+
+    let values = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+fn insert(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    match HeaderValue::from_str(value) {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(err) => log::warn!("Invalid {name} header value {value:?}: {err}"),
+    }
+}
+
+/// IPv6 nodes must be quoted and bracketed, see RFC 7239 section 6.
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{ip}]\""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::HOST;
+
+    use super::*;
+
+    fn headers(pairs: &[(HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn replaces_untrusted_headers() {
+        let mut headers = headers(&[
+            (HOST, "demo.example.org"),
+            (X_FORWARDED_FOR, "10.0.0.1"),
+            (X_FORWARDED_PROTO, "https"),
+        ]);
+        set_forwarded_headers(
+            &mut headers,
+            "1.2.3.4".parse().unwrap(),
+            "http",
+            "demo.example.org",
+            false,
+        );
+
+        assert_eq!(headers[X_FORWARDED_FOR], "1.2.3.4");
+        assert_eq!(headers[X_FORWARDED_PROTO], "http");
+        assert_eq!(headers[X_FORWARDED_HOST], "demo.example.org");
+        assert_eq!(
+            headers[FORWARDED],
+            "for=1.2.3.4;proto=http;host=\"demo.example.org\""
+        );
+    }
+
+    #[test]
+    fn client_addr_works() {
+        let peer = "10.0.0.2".parse().unwrap();
+        let headers = headers(&[(X_FORWARDED_FOR, "1.1.1.1, 2.2.2.2")]);
+
+        assert_eq!(
+            client_addr(&headers, peer, true),
+            "2.2.2.2".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(client_addr(&headers, peer, false), peer);
+        assert_eq!(client_addr(&HeaderMap::new(), peer, true), peer);
+    }
+
+    #[test]
+    fn appends_to_trusted_headers() {
+        let mut headers = headers(&[
+            (HOST, "demo.example.org"),
+            (X_FORWARDED_FOR, "10.0.0.1"),
+            (X_FORWARDED_PROTO, "https"),
+            (FORWARDED, "for=10.0.0.1;proto=https"),
+        ]);
+        set_forwarded_headers(
+            &mut headers,
+            "::1".parse().unwrap(),
+            "http",
+            "demo.example.org",
+            true,
+        );
+
+        assert_eq!(headers[X_FORWARDED_FOR], "10.0.0.1, ::1");
+        assert_eq!(headers[X_FORWARDED_PROTO], "https");
+        assert_eq!(
+            headers[FORWARDED],
+            "for=10.0.0.1;proto=https, for=\"[::1]\";proto=http;host=\"demo.example.org\""
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! Copy between upgraded connections until both directions are idle for too long,
+//! so a forgotten WebSocket doesn't keep a tunnel socket forever.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{copy_bidirectional, AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep_until, Instant},
+};
+
+/// Copy in both directions like `copy_bidirectional`, `None` if nothing was
+/// read or written within `idle_timeout`.
+pub async fn copy_until_idle<A, B>(
+    a: A,
+    b: B,
+    idle_timeout: Duration,
+) -> io::Result<Option<(u64, u64)>>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let last_active = Arc::new(Mutex::new(Instant::now()));
+    let mut a = ActiveStream::new(a, last_active.clone());
+    let mut b = ActiveStream::new(b, last_active.clone());
+
+    let copy = copy_bidirectional(&mut a, &mut b);
+    tokio::pin!(copy);
+    loop {
+        let deadline = *last_active.lock().unwrap() + idle_timeout;
+        tokio::select! {
+            transferred = &mut copy => return transferred.map(Some),
+            _ = sleep_until(deadline) => {
+                if last_active.lock().unwrap().elapsed() >= idle_timeout {
+                    return Ok(None);
+                }
+            }
+        }
+    }
+}
+
+/// A stream wrapper which records when bytes were last read or written.
+struct ActiveStream<S> {
+    inner: S,
+    last_active: Arc<Mutex<Instant>>,
+}
+
+impl<S> ActiveStream<S> {
+    fn new(inner: S, last_active: Arc<Mutex<Instant>>) -> Self {
+        ActiveStream { inner, last_active }
+    }
+
+    fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ActiveStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            if buf.filled().len() > filled {
+                self.touch();
+            }
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ActiveStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            if written > 0 {
+                self.touch();
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn idle_connection_is_closed() {
+        let (a, mut a_peer) = tokio::io::duplex(64);
+        let (b, mut b_peer) = tokio::io::duplex(64);
+        let copy = tokio::spawn(copy_until_idle(a, b, Duration::from_millis(200)));
+
+        // activity in either direction keeps the connection open
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(120)).await;
+            a_peer.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            b_peer.read_exact(&mut buf).await.unwrap();
+        }
+        assert!(!copy.is_finished());
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(copy.await.unwrap().unwrap(), None);
+    }
+}
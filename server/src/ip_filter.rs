@@ -0,0 +1,73 @@
+//! Visitor addresses allowed or denied per tunnel, e.g. office networks or the
+//! published ranges of a webhook provider.
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    /// Parse comma separated CIDRs or addresses, `None` if there are no rules.
+    pub fn parse(allow: Option<&str>, deny: Option<&str>) -> Result<Option<Self>, String> {
+        let filter = IpFilter {
+            allow: parse_nets(allow)?,
+            deny: parse_nets(deny)?,
+        };
+
+        Ok((!filter.allow.is_empty() || !filter.deny.is_empty()).then_some(filter))
+    }
+
+    /// Denied addresses are refused even if they're allowed as well,
+    /// all others are permitted if no allow rule is set.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+fn parse_nets(value: Option<&str>) -> Result<Vec<IpNet>, String> {
+    value
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|net| !net.is_empty())
+        .map(|net| {
+            net.parse::<IpNet>()
+                .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("{net} is not a valid CIDR or address"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits_works() {
+        let filter = IpFilter::parse(Some("10.0.0.0/8, 192.168.1.1"), Some("10.0.0.1"))
+            .unwrap()
+            .unwrap();
+
+        assert!(filter.permits("10.1.2.3".parse().unwrap()));
+        assert!(filter.permits("192.168.1.1".parse().unwrap()));
+        assert!(filter.permits("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!filter.permits("10.0.0.1".parse().unwrap()));
+        assert!(!filter.permits("8.8.8.8".parse().unwrap()));
+
+        let filter = IpFilter::parse(None, Some("8.8.8.0/24")).unwrap().unwrap();
+        assert!(filter.permits("1.1.1.1".parse().unwrap()));
+        assert!(!filter.permits("8.8.8.8".parse().unwrap()));
+
+        assert_eq!(IpFilter::parse(None, Some("")), Ok(None));
+        assert!(IpFilter::parse(Some("office"), None).is_err());
+    }
+}
@@ -5,29 +5,54 @@
 #[macro_use]
 extern crate lazy_static;
 
-use std::time::Duration;
-use std::{net::SocketAddr, sync::Arc};
+use std::time::{Duration, Instant};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 
 use actix_web::{web, App, HttpServer};
 use anyhow::Result;
 use dotenv::dotenv;
-use hyper::{server::conn::http1, service::service_fn};
-use tokio::{net::TcpListener, sync::Mutex, time::timeout};
+pub use ipnet::IpNet;
+use tokio::{
+    net::TcpListener,
+    sync::Mutex,
+    time::{interval, MissedTickBehavior},
+};
+use tokio_rustls::TlsAcceptor;
 
-use crate::api::{api_status, request_endpoint};
+pub use crate::access_log::{AccessLogConfig, AccessLogFormat};
+pub use crate::auth::{Auth, CfWorkerStore, FileStore, Grant, TokenAuth};
+pub use crate::state::{Limits, Timings, TunnelType};
+
+use crate::access_log::AccessLog;
+use crate::api::{
+    api_status, close_tunnel, request_endpoint, request_random_endpoint, server_metrics,
+    tunnel_status,
+};
 use crate::config::Config;
-use crate::proxy::proxy_handler;
+use crate::mux::accept_multiplexed;
+use crate::pages::ErrorPages;
+use crate::proxy::{serve_connection, ProxyState};
 use crate::state::{ClientManager, State};
+use crate::tls::CertResolver;
 
+mod access_log;
 mod api;
-mod auth;
+pub mod auth;
 mod config;
 mod error;
+mod forwarded;
+mod idle;
+mod ip_filter;
+mod metrics;
+mod mux;
+mod pages;
+mod pool;
 mod proxy;
+mod proxy_protocol;
 mod state;
-
-/// The interval between cleanup checks
-const CLEANUP_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+mod stats;
+mod tls;
+mod visitor_auth;
 
 lazy_static! {
     static ref CONFIG: Config = {
@@ -43,6 +68,40 @@ pub struct ServerConfig {
     pub max_sockets: u8,
     pub proxy_port: u16,
     pub require_auth: bool,
+    /// Validates the credentials of tunnel clients if `require_auth` is set,
+    /// `None` uses the Cloudflare KV store.
+    pub auth: Option<Box<dyn Auth + Send + Sync>>,
+    /// Refuse credentials in the query string of old clients, which may end up
+    /// in access logs, instead of the `Authorization` header.
+    pub reject_query_credential: bool,
+    /// Port accepting multiplexed client connections, disabled if `None`.
+    pub mux_port: Option<u16>,
+    /// Visitor requests each tunnel queues while all its sockets are busy.
+    pub queue_depth: usize,
+    /// How long a queued visitor request waits for a socket before a 503.
+    pub queue_timeout: Duration,
+    /// Directory of templates overriding the built-in error pages.
+    pub error_pages: Option<PathBuf>,
+    /// Keep `X-Forwarded-*` and `Forwarded` headers set by a proxy in front of
+    /// the server (e.g. Caddy) instead of replacing them.
+    pub trust_forwarded_headers: bool,
+    /// Read the visitor address from a PROXY protocol v1/v2 header.
+    pub proxy_protocol: bool,
+    /// Sources required to send the PROXY protocol header, all if empty,
+    /// connections from others are served as is.
+    pub proxy_protocol_trusted: Vec<IpNet>,
+    /// Directory with the certificate (`fullchain.pem` or `cert.pem`) and key
+    /// (`privkey.pem` or `key.pem`) to terminate TLS on the proxy and api ports,
+    /// reloaded when the files change. Implies `secure`.
+    pub tls_dir: Option<PathBuf>,
+    /// When unused tunnels are cleaned up and how their sockets are checked.
+    pub timings: Timings,
+    /// Limits of visitor requests, tunnels may request lower ones.
+    pub limits: Limits,
+    /// Write an entry for every visitor request, disabled if `None`.
+    pub access_log: Option<AccessLogConfig>,
+    /// Serve Prometheus metrics at `/metrics` on the api port.
+    pub metrics: bool,
 }
 
 /// Start the proxy use low level api from hyper.
@@ -55,7 +114,41 @@ pub async fn start(config: ServerConfig) -> Result<()> {
         max_sockets,
         proxy_port,
         require_auth,
+        auth,
+        reject_query_credential,
+        mux_port,
+        queue_depth,
+        queue_timeout,
+        error_pages,
+        trust_forwarded_headers,
+        proxy_protocol,
+        proxy_protocol_trusted,
+        tls_dir,
+        timings,
+        limits,
+        access_log,
+        metrics,
     } = config;
+    // a zero interval panics, a zero accept timeout spins the client listeners
+    if timings.cleanup_interval.is_zero() || timings.accept_timeout.is_zero() {
+        anyhow::bail!("Cleanup interval and accept timeout must be longer than zero");
+    }
+    // zero timeouts fail every visitor request or upgrade at once
+    if limits.header_read_timeout.is_zero()
+        || limits.upstream_timeout.is_zero()
+        || limits.upgrade_idle_timeout.is_zero()
+    {
+        anyhow::bail!("Header read, upstream and upgrade idle timeouts must be longer than zero");
+    }
+    let tls = match tls_dir {
+        Some(dir) => {
+            let resolver = Arc::new(CertResolver::load(&dir)?);
+            resolver.clone().watch();
+            Some(resolver)
+        }
+        None => None,
+    };
+    let secure = secure || tls.is_some();
     log::info!("Api server listens at {} {}", &domain, api_port);
     log::info!(
         "Start proxy server at {} {}, options: {} {}, require auth: {}",
@@ -66,55 +159,103 @@ pub async fn start(config: ServerConfig) -> Result<()> {
         require_auth
     );
 
-    let manager = Arc::new(Mutex::new(ClientManager::new(max_sockets)));
+    let mut manager = ClientManager::new(max_sockets);
+    manager.queue_depth = queue_depth;
+    manager.queue_timeout = queue_timeout;
+    manager.timings = timings;
+    manager.limits = limits;
+    let manager = Arc::new(Mutex::new(manager));
     let api_state = web::Data::new(State {
         manager: manager.clone(),
-        max_sockets,
         require_auth,
+        auth: match auth {
+            Some(auth) => auth,
+            None => Box::new(CfWorkerStore::new()?),
+        },
+        reject_query_credential,
         secure,
         domain,
+        mux_port,
+        started_at: Instant::now(),
+    });
+
+    if let Some(mux_port) = mux_port {
+        log::info!("Accept multiplexed clients at {}", mux_port);
+        let mux_addr: SocketAddr = ([0, 0, 0, 0], mux_port).into();
+        let mux_listener = TcpListener::bind(mux_addr).await?;
+        tokio::spawn(accept_multiplexed(mux_listener, manager.clone()));
+    }
+
+    let proxy_state = Arc::new(ProxyState {
+        manager: manager.clone(),
+        pages: match error_pages {
+            Some(dir) => ErrorPages::load(&dir)?,
+            None => ErrorPages::default(),
+        },
+        secure,
+        trust_forwarded_headers,
+        proxy_protocol,
+        proxy_protocol_trusted,
+        tls: match &tls {
+            Some(resolver) => Some(TlsAcceptor::from(Arc::new(tls::server_config(
+                resolver.clone(),
+                &[b"h2", b"http/1.1"],
+            )?))),
+            None => None,
+        },
+        access_log: match access_log {
+            Some(config) => Some(AccessLog::open(config)?),
+            None => None,
+        },
+        limits,
     });
 
     let proxy_addr: SocketAddr = ([0, 0, 0, 0], proxy_port).into();
     let listener = TcpListener::bind(proxy_addr).await?;
     tokio::spawn(async move {
         loop {
-            match timeout(CLEANUP_CHECK_INTERVAL, listener.accept()).await {
-                Ok(Ok((stream, _))) => {
-                    log::info!("Accepted a new proxy request");
-
-                    let proxy_manager = manager.clone();
-                    let service = service_fn(move |req| proxy_handler(req, proxy_manager.clone()));
-
-                    tokio::spawn(async move {
-                        if let Err(err) = http1::Builder::new()
-                            .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
-                            .with_upgrades()
-                            .await
-                        {
-                            log::error!("Failed to serve connection: {:?}", err);
-                        }
-                    });
-                }
-                Ok(Err(e)) => log::error!("Failed to accept the request: {:?}", e),
-                Err(_) => {
-                    // timeout, cleanup old connections
-                    let mut manager = manager.lock().await;
-                    manager.cleanup().await;
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    tokio::spawn(serve_connection(stream, peer, proxy_state.clone()));
                 }
+                Err(e) => log::error!("Failed to accept the request: {:?}", e),
             }
         }
     });
 
-    HttpServer::new(move || {
+    // cleanup old connections, even while the proxy is busy
+    tokio::spawn(async move {
+        let mut interval = interval(timings.cleanup_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            manager.lock().await.cleanup().await;
+        }
+    });
+
+    let api_server = HttpServer::new(move || {
         App::new()
             .app_data(api_state.clone())
             .service(api_status)
+            .service(tunnel_status)
+            .service(close_tunnel)
+            // before the endpoint requests, which would match `/metrics` too
+            .configure(|cfg| {
+                if metrics {
+                    cfg.service(server_metrics);
+                }
+            })
+            .service(request_random_endpoint)
             .service(request_endpoint)
-    })
-    .bind(("0.0.0.0", api_port))?
-    .run()
-    .await?;
+    });
+    let api_server = match tls {
+        Some(resolver) => api_server.bind_rustls_0_23(
+            ("0.0.0.0", api_port),
+            tls::server_config(resolver, &[b"http/1.1"])?,
+        )?,
+        None => api_server.bind(("0.0.0.0", api_port))?,
+    };
+    api_server.run().await?;
 
     Ok(())
 }
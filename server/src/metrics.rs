@@ -0,0 +1,194 @@
+//! Prometheus metrics of the server, served at `/metrics` on the api port.
+
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::error::ServerError;
+use crate::state::ClientManager;
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::new();
+}
+
+pub struct Metrics {
+    registry: Registry,
+    tunnels: IntGauge,
+    pooled_sockets: IntGaugeVec,
+    pub registrations: IntCounter,
+    pub auth_failures: IntCounter,
+    requests: IntCounterVec,
+    proxy_errors: IntCounterVec,
+    pub upgrades: IntCounter,
+    /// bytes read from and written to tunnel clients
+    pub bytes_in: IntCounter,
+    pub bytes_out: IntCounter,
+    /// seconds until the response headers of a visitor request
+    pub request_duration: Histogram,
+    /// seconds a visitor request waits for a stream to the tunnel client
+    pub socket_wait: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let bytes = IntCounterVec::new(
+            Opts::new(
+                "localtunnel_bytes_total",
+                "Bytes read from (in) and written to (out) tunnel clients.",
+            ),
+            &["direction"],
+        )
+        .unwrap();
+        let buckets = exponential_buckets(0.001, 4.0, 10).unwrap();
+
+        let metrics = Metrics {
+            registry: Registry::new(),
+            tunnels: IntGauge::new("localtunnel_tunnels", "Registered tunnels.").unwrap(),
+            pooled_sockets: IntGaugeVec::new(
+                Opts::new(
+                    "localtunnel_pooled_sockets",
+                    "Idle sockets and connections of each tunnel.",
+                ),
+                &["tunnel"],
+            )
+            .unwrap(),
+            registrations: IntCounter::new(
+                "localtunnel_registrations_total",
+                "Tunnels registered.",
+            )
+            .unwrap(),
+            auth_failures: IntCounter::new(
+                "localtunnel_auth_failures_total",
+                "Registrations refused for a missing, invalid or insufficient credential.",
+            )
+            .unwrap(),
+            requests: IntCounterVec::new(
+                Opts::new(
+                    "localtunnel_requests_total",
+                    "Visitor requests by status class.",
+                ),
+                &["status"],
+            )
+            .unwrap(),
+            proxy_errors: IntCounterVec::new(
+                Opts::new(
+                    "localtunnel_proxy_errors_total",
+                    "Visitor requests failed for a missing tunnel or stream.",
+                ),
+                &["error"],
+            )
+            .unwrap(),
+            upgrades: IntCounter::new(
+                "localtunnel_upgrades_total",
+                "Upgraded connections, e.g. WebSocket sessions.",
+            )
+            .unwrap(),
+            bytes_in: bytes.with_label_values(&["in"]),
+            bytes_out: bytes.with_label_values(&["out"]),
+            request_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "localtunnel_request_duration_seconds",
+                    "Seconds until the response headers of visitor requests.",
+                )
+                .buckets(buckets.clone()),
+            )
+            .unwrap(),
+            socket_wait: Histogram::with_opts(
+                HistogramOpts::new(
+                    "localtunnel_socket_wait_seconds",
+                    "Seconds visitor requests wait for a stream to the tunnel client.",
+                )
+                .buckets(buckets),
+            )
+            .unwrap(),
+        };
+
+        let registry = &metrics.registry;
+        registry
+            .register(Box::new(metrics.tunnels.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.pooled_sockets.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.registrations.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.auth_failures.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.requests.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.proxy_errors.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.upgrades.clone()))
+            .unwrap();
+        registry.register(Box::new(bytes)).unwrap();
+        registry
+            .register(Box::new(metrics.request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(metrics.socket_wait.clone()))
+            .unwrap();
+        metrics
+    }
+
+    /// Count a visitor request by the class of its status, e.g. `5xx`.
+    pub fn observe_response(&self, status: u16) {
+        self.requests
+            .with_label_values(&[&format!("{}xx", status / 100)])
+            .inc();
+    }
+
+    pub fn observe_error(&self, err: &anyhow::Error) {
+        let error = match err.downcast_ref::<ServerError>() {
+            Some(ServerError::EmptyConnection) => "empty_connection",
+            Some(ServerError::ProxyNotReady) => "proxy_not_ready",
+            _ => return,
+        };
+        self.proxy_errors.with_label_values(&[error]).inc();
+    }
+
+    /// The text exposition of all metrics, with the gauges read from the tunnels.
+    pub async fn render(&self, manager: &ClientManager) -> String {
+        self.tunnels.set(manager.clients.len() as i64);
+        self.pooled_sockets.reset();
+        for (endpoint, client) in &manager.clients {
+            let sockets = client.lock().await.connected_sockets().await;
+            self.pooled_sockets
+                .with_label_values(&[endpoint])
+                .set(sockets as i64);
+        }
+
+        let mut buf = vec![];
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            log::error!("Failed to encode metrics: {err}");
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn render_metrics_works() {
+        let mut manager = ClientManager::new(10);
+        manager
+            .put("demo".to_string(), Default::default())
+            .await
+            .unwrap();
+        METRICS.observe_response(502);
+        METRICS.observe_error(&ServerError::ProxyNotReady.into());
+
+        let text = METRICS.render(&manager).await;
+        assert!(text.contains("localtunnel_tunnels 1"));
+        assert!(text.contains("localtunnel_pooled_sockets{tunnel=\"demo\"} 0"));
+        assert!(text.contains("localtunnel_requests_total{status=\"5xx\"}"));
+        assert!(text.contains("localtunnel_proxy_errors_total{error=\"proxy_not_ready\"}"));
+    }
+}
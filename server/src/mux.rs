@@ -0,0 +1,389 @@
+//! Multiplexed transport, a tunnel client opens a single control connection
+//! to the server's fixed mux port, visitor streams are then opened over it
+//! with yamux instead of pooling one tcp socket per visitor connection.
+
+use std::{
+    future::poll_fn,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot, Mutex, OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+
+use crate::auth::constant_time_eq;
+use crate::state::{set_keepalive, ClientManager, Transport};
+
+/// How long a new control connection has to send its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Upper bound of the handshake line `<id> <secret>\n`.
+const MAX_HANDSHAKE_LEN: usize = 256;
+
+type OpenRequest = oneshot::Sender<yamux::Result<yamux::Stream>>;
+
+/// Handle to the control connection of a multiplexed client.
+#[derive(Clone)]
+pub struct MuxHandle {
+    requests: mpsc::Sender<OpenRequest>,
+    /// Open streams are limited to the client's `max_sockets`, like its sockets.
+    streams: Arc<Semaphore>,
+    /// requests waiting for a stream, limited to the client's `queue_depth`
+    waiting: Arc<AtomicUsize>,
+    queue_depth: usize,
+}
+
+impl MuxHandle {
+    /// Queue for a stream like `Client::wait`, `None` if the queue is full.
+    pub fn wait(&self) -> Option<MuxWaiter> {
+        // waiters which will get one of the free streams aren't queued
+        let queued = self.waiting.load(Ordering::SeqCst);
+        if queued >= self.queue_depth + self.streams.available_permits() {
+            log::warn!("Reached queue max: {}", self.queue_depth);
+            return None;
+        }
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        Some(MuxWaiter { mux: self.clone() })
+    }
+
+    /// If the control connection has been closed.
+    pub fn is_closed(&self) -> bool {
+        self.requests.is_closed()
+    }
+}
+
+/// A request queued for a stream, it leaves the queue once it gets one or is dropped.
+pub struct MuxWaiter {
+    mux: MuxHandle,
+}
+
+impl MuxWaiter {
+    /// Open a new logical stream to the tunnel client, waiting while the client
+    /// has as many streams open as it may have sockets.
+    pub async fn open(self) -> Option<MuxStream> {
+        if self.mux.is_closed() {
+            return None;
+        }
+        let permit = self.mux.streams.clone().acquire_owned().await.ok()?;
+        let requests = self.mux.requests.clone();
+        // leave the queue once a stream is ours
+        drop(self);
+
+        let (tx, rx) = oneshot::channel();
+        requests.send(tx).await.ok()?;
+
+        match rx.await.ok()? {
+            Ok(stream) => Some(MuxStream {
+                inner: stream.compat(),
+                _permit: permit,
+            }),
+            Err(err) => {
+                log::warn!("Failed to open multiplexed stream: {:?}", err);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for MuxWaiter {
+    fn drop(&mut self) {
+        self.mux.waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A logical stream to the tunnel client, holding one of its stream permits.
+pub struct MuxStream {
+    inner: Compat<yamux::Stream>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Accept control connections of multiplexed clients.
+pub async fn accept_multiplexed(listener: TcpListener, manager: Arc<Mutex<ClientManager>>) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                log::info!("new multiplexed client connection: {:?}", addr);
+
+                let manager = manager.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = attach(socket, manager).await {
+                        log::warn!("Rejected multiplexed connection {addr}: {err}");
+                    }
+                });
+            }
+            Err(e) => log::info!("Couldn't get multiplexed client: {:?}", e),
+        }
+    }
+}
+
+/// Authenticate the control connection and attach it to its client.
+async fn attach(mut socket: TcpStream, manager: Arc<Mutex<ClientManager>>) -> io::Result<()> {
+    let handshake = timeout(HANDSHAKE_TIMEOUT, read_handshake(&mut socket)).await??;
+    let (id, secret) = handshake
+        .split_once(' ')
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed handshake"))?;
+
+    let client = manager
+        .lock()
+        .await
+        .clients
+        .get(id)
+        .cloned()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown tunnel"))?;
+    let mut client = client.lock().await;
+    if !constant_time_eq(secret.as_bytes(), client.secret.as_bytes()) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "invalid secret",
+        ));
+    }
+    // clients with a port of their own are limited by the sockets they open there
+    if client.transport != Transport::Multiplex {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "tunnel is not registered as multiplexed",
+        ));
+    }
+
+    set_keepalive(&socket, &client.timings);
+    let connection = yamux::Connection::new(
+        socket.compat(),
+        yamux::Config::default(),
+        yamux::Mode::Server,
+    );
+    let (requests, receiver) = mpsc::channel(16);
+    tokio::spawn(drive(connection, receiver));
+
+    log::debug!("Attach multiplexed connection to {id}");
+    client.mux = Some(MuxHandle {
+        requests,
+        streams: Arc::new(Semaphore::new(usize::from(client.max_sockets))),
+        waiting: Arc::default(),
+        queue_depth: client.queue_depth,
+    });
+    Ok(())
+}
+
+/// Read the handshake line byte by byte, so no yamux frame is consumed.
+async fn read_handshake(socket: &mut TcpStream) -> io::Result<String> {
+    let mut line = Vec::new();
+    loop {
+        let byte = socket.read_u8().await?;
+        if byte == b'\n' {
+            break;
+        }
+        if line.len() >= MAX_HANDSHAKE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "handshake too long",
+            ));
+        }
+        line.push(byte);
+    }
+
+    String::from_utf8(line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Drive the yamux connection, opening outbound streams on request.
+/// The tunnel client is not allowed to open streams itself.
+async fn drive(
+    mut connection: yamux::Connection<Compat<TcpStream>>,
+    mut requests: mpsc::Receiver<OpenRequest>,
+) {
+    let mut pending: Option<OpenRequest> = None;
+
+    poll_fn(|cx| loop {
+        if pending.is_none() {
+            match requests.poll_recv(cx) {
+                Poll::Ready(Some(request)) => pending = Some(request),
+                // all handles are dropped, the client is gone
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => {}
+            }
+        }
+
+        if let Some(request) = pending.take() {
+            match connection.poll_new_outbound(cx) {
+                Poll::Ready(result) => {
+                    let _ = request.send(result);
+                    continue;
+                }
+                Poll::Pending => pending = Some(request),
+            }
+        }
+
+        match connection.poll_next_inbound(cx) {
+            Poll::Ready(Some(Ok(_stream))) => {
+                log::warn!("Drop stream opened by multiplexed client");
+            }
+            Poll::Ready(Some(Err(err))) => {
+                log::info!("Multiplexed connection failed: {:?}", err);
+                return Poll::Ready(());
+            }
+            Poll::Ready(None) => return Poll::Ready(()),
+            Poll::Pending => return Poll::Pending,
+        }
+    })
+    .await;
+
+    log::debug!("Multiplexed connection closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncWriteExt, BufReader};
+
+    use super::*;
+    use crate::state::{Client, TunnelOptions};
+
+    /// Register `demo` and attach a tunnel client echoing every stream opened by the server.
+    async fn echo_client(options: TunnelOptions) -> Arc<Mutex<Client>> {
+        let manager = Arc::new(Mutex::new(ClientManager::new(10)));
+        let secret = manager
+            .lock()
+            .await
+            .put("demo".to_string(), options)
+            .await
+            .unwrap()
+            .secret;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(accept_multiplexed(listener, manager.clone()));
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket
+            .write_all(format!("demo {secret}\n").as_bytes())
+            .await
+            .unwrap();
+        tokio::spawn(async move {
+            let mut connection = yamux::Connection::new(
+                socket.compat(),
+                yamux::Config::default(),
+                yamux::Mode::Client,
+            );
+            while let Some(Ok(stream)) = poll_fn(|cx| connection.poll_next_inbound(cx)).await {
+                tokio::spawn(async move {
+                    let (reader, mut writer) = tokio::io::split(stream.compat());
+                    let _ = tokio::io::copy(&mut BufReader::new(reader), &mut writer).await;
+                });
+            }
+        });
+
+        let client = manager.lock().await.clients["demo"].clone();
+        client
+    }
+
+    /// The control connection once it's attached, `None` if it isn't within a second.
+    async fn attached(client: &Mutex<Client>) -> Option<MuxHandle> {
+        timeout(Duration::from_secs(1), async {
+            loop {
+                if let Some(mux) = client.lock().await.mux.clone() {
+                    return mux;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .ok()
+    }
+
+    #[tokio::test]
+    async fn open_stream_over_multiplexed_connection() {
+        let client = echo_client(TunnelOptions {
+            transport: Transport::Multiplex,
+            ..Default::default()
+        })
+        .await;
+        let mux = attached(&client).await.expect("control connection");
+        let mut stream = timeout(Duration::from_secs(5), mux.wait().unwrap().open())
+            .await
+            .unwrap()
+            .unwrap();
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn open_streams_are_limited_to_max_sockets() {
+        let client = echo_client(TunnelOptions {
+            transport: Transport::Multiplex,
+            max_sockets: Some(1),
+            ..Default::default()
+        })
+        .await;
+        let mux = attached(&client).await.expect("control connection");
+
+        let first = mux.wait().unwrap().open().await.unwrap();
+        let wait = Duration::from_millis(200);
+        assert!(timeout(wait, mux.wait().unwrap().open()).await.is_err());
+        drop(first);
+        assert!(timeout(wait, mux.wait().unwrap().open())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn waiters_are_limited_to_queue_depth() {
+        let mux = MuxHandle {
+            requests: mpsc::channel(1).0,
+            streams: Arc::new(Semaphore::new(1)),
+            waiting: Arc::default(),
+            queue_depth: 1,
+        };
+
+        let _free = mux.wait().expect("a stream is free");
+        let queued = mux.wait().expect("queue has room");
+        assert!(mux.wait().is_none(), "queue should be full");
+        drop(queued);
+        assert!(mux.wait().is_some());
+    }
+
+    #[tokio::test]
+    async fn socket_clients_cannot_attach() {
+        let client = echo_client(TunnelOptions::default()).await;
+        assert!(attached(&client).await.is_none());
+    }
+}
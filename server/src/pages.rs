@@ -0,0 +1,126 @@
+//! Error pages returned to visitors when a request can't be proxied.
+//!
+//! Operators can override them with templates in a directory, `<status>.html`
+//! is used for a specific status code and `error.html` for all the others.
+//! Templates may contain `{{status}}`, `{{reason}}`, `{{message}}` and `{{host}}`.
+
+use std::{collections::HashMap, fs, io, path::Path};
+
+use hyper::StatusCode;
+
+const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>{{status}} {{reason}}</title>
+</head>
+<body>
+  <h1>{{status}} {{reason}}</h1>
+  <p>{{message}}</p>
+  <hr>
+  <p>localtunnel</p>
+</body>
+</html>
+"#;
+
+#[derive(Default)]
+pub struct ErrorPages {
+    templates: HashMap<u16, String>,
+    fallback: Option<String>,
+}
+
+impl ErrorPages {
+    /// Load the templates from the directory, missing ones use the built-in page.
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let mut pages = ErrorPages::default();
+
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("html") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+
+            if name == "error" {
+                pages.fallback = Some(fs::read_to_string(&path)?);
+            } else if let Ok(status) = name.parse::<u16>() {
+                pages.templates.insert(status, fs::read_to_string(&path)?);
+            }
+        }
+        log::info!(
+            "Loaded {} error page templates from {}",
+            pages.templates.len() + usize::from(pages.fallback.is_some()),
+            dir.display()
+        );
+
+        Ok(pages)
+    }
+
+    pub fn render(&self, status: StatusCode, host: Option<&str>) -> String {
+        let template = self
+            .templates
+            .get(&status.as_u16())
+            .or(self.fallback.as_ref())
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_TEMPLATE);
+
+        template
+            .replace("{{status}}", status.as_str())
+            .replace("{{reason}}", status.canonical_reason().unwrap_or_default())
+            .replace("{{message}}", message(status))
+            .replace("{{host}}", &escape(host.unwrap_or_default()))
+    }
+}
+
+fn message(status: StatusCode) -> &'static str {
+    match status {
+        StatusCode::BAD_REQUEST => "The request must contain a valid Host header.",
+        StatusCode::UNAUTHORIZED => "Visiting this tunnel requires authentication.",
+        StatusCode::FORBIDDEN => "Your address is not allowed to visit this tunnel.",
+        StatusCode::NOT_FOUND => {
+            "There is no tunnel for this host, the tunnel client may not be connected."
+        }
+        StatusCode::BAD_GATEWAY => "The tunnel client is not reachable.",
+        StatusCode::SERVICE_UNAVAILABLE => "The tunnel is busy, please retry later.",
+        StatusCode::GATEWAY_TIMEOUT => "The tunnel client did not respond in time.",
+        _ => "The request could not be proxied.",
+    }
+}
+
+/// The host comes from the visitor, escape it before putting into html.
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_uses_overridden_template() {
+        let mut pages = ErrorPages::default();
+        pages
+            .templates
+            .insert(404, "{{status}} {{host}} not found".to_string());
+        pages.fallback = Some("oops {{reason}}".to_string());
+
+        assert_eq!(
+            pages.render(StatusCode::NOT_FOUND, Some("<demo>.example.org")),
+            "404 &lt;demo&gt;.example.org not found"
+        );
+        assert_eq!(
+            pages.render(StatusCode::BAD_GATEWAY, None),
+            "oops Bad Gateway"
+        );
+        assert!(ErrorPages::default()
+            .render(StatusCode::GATEWAY_TIMEOUT, None)
+            .contains("<h1>504 Gateway Timeout</h1>"));
+    }
+}
@@ -0,0 +1,142 @@
+//! Connections to the tunnel client kept open across visitor requests, so a page
+//! with many assets doesn't take a new client socket for every request.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use http_body_util::Limited;
+use hyper::{
+    body::Incoming,
+    client::conn::{http1, http2},
+};
+use tokio::sync::oneshot;
+
+/// Visitor request bodies, limited to the size the tunnel allows.
+pub type RequestBody = Limited<Incoming>;
+
+pub type Http1Sender = http1::SendRequest<RequestBody>;
+pub type Http2Sender = http2::SendRequest<RequestBody>;
+
+/// Shared by the client and the requests using its connections.
+pub type SharedSenderPool = Arc<Mutex<SenderPool>>;
+
+/// Idle HTTP/1.1 connections and requests waiting for one to be released,
+/// or the HTTP/2 connection all requests are multiplexed over.
+#[derive(Default)]
+pub struct SenderPool {
+    idle: Vec<Http1Sender>,
+    waiters: VecDeque<oneshot::Sender<Http1Sender>>,
+    http2: Option<Http2Sender>,
+}
+
+impl SenderPool {
+    /// An idle connection, or a receiver of the next one released if all are busy.
+    pub fn checkout(&mut self) -> Result<Http1Sender, oneshot::Receiver<Http1Sender>> {
+        while let Some(sender) = self.idle.pop() {
+            if sender.is_ready() {
+                return Ok(sender);
+            }
+        }
+
+        let (tx, rx) = oneshot::channel();
+        self.waiters.retain(|waiter| !waiter.is_closed());
+        self.waiters.push_back(tx);
+        Err(rx)
+    }
+
+    /// Hand the connection to a waiting request, or keep it until the next one.
+    fn release(&mut self, mut sender: Http1Sender) {
+        while let Some(waiter) = self.waiters.pop_front() {
+            match waiter.send(sender) {
+                Ok(()) => return,
+                // the request got a new socket or has been canceled
+                Err(unused) => sender = unused,
+            }
+        }
+        self.idle.push(sender);
+    }
+
+    /// Number of open connections waiting for the next request, the HTTP/2
+    /// connection included as it's never checked out.
+    pub fn idle(&self) -> usize {
+        self.idle
+            .iter()
+            .filter(|sender| !sender.is_closed())
+            .count()
+            + usize::from(self.http2().is_some())
+    }
+
+    pub fn http2(&self) -> Option<Http2Sender> {
+        self.http2
+            .as_ref()
+            .filter(|sender| !sender.is_closed())
+            .cloned()
+    }
+
+    pub fn set_http2(&mut self, sender: Http2Sender) {
+        self.http2 = Some(sender);
+    }
+}
+
+/// Put the connection back into the pool once the response has been read,
+/// connections closed by either side are dropped.
+pub fn release_when_ready(pool: SharedSenderPool, mut sender: Http1Sender) {
+    tokio::spawn(async move {
+        if sender.ready().await.is_ok() {
+            pool.lock().unwrap().release(sender);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper_util::rt::TokioIo;
+
+    use super::*;
+
+    /// A ready sender and the peer keeping its connection open.
+    async fn sender() -> (Http1Sender, tokio::io::DuplexStream) {
+        let (io, peer) = tokio::io::duplex(64);
+        let (mut sender, conn) = http1::handshake(TokioIo::new(io)).await.unwrap();
+        tokio::spawn(conn);
+        sender.ready().await.unwrap();
+        (sender, peer)
+    }
+
+    #[tokio::test]
+    async fn released_sender_goes_to_waiter_first() {
+        let mut pool = SenderPool::default();
+        let waiter = pool.checkout().expect_err("no idle sender");
+
+        let (first, _first_peer) = sender().await;
+        pool.release(first);
+        assert!(waiter.await.is_ok());
+        assert_eq!(pool.idle(), 0);
+
+        let (second, _second_peer) = sender().await;
+        pool.release(second);
+        assert_eq!(pool.idle(), 1);
+        assert!(pool.checkout().is_ok());
+        assert!(pool.checkout().is_err());
+    }
+
+    #[tokio::test]
+    async fn http2_sender_counts_as_idle() {
+        let mut pool = SenderPool::default();
+        let (io, peer) = tokio::io::duplex(64);
+        let (sender, conn) =
+            http2::handshake(hyper_util::rt::TokioExecutor::new(), TokioIo::new(io))
+                .await
+                .unwrap();
+        let conn = tokio::spawn(conn);
+        pool.set_http2(sender);
+        assert_eq!(pool.idle(), 1);
+
+        // the connection closes once its peer goes away
+        drop(peer);
+        let _ = conn.await;
+        assert_eq!(pool.idle(), 0);
+    }
+}
@@ -1,51 +1,372 @@
-use std::sync::Arc;
+use std::{
+    convert::Infallible,
+    error::Error,
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::Result;
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt, Full, LengthLimitError, Limited};
 use hyper::{
     body::Incoming,
-    header::{HOST, UPGRADE},
+    header::{
+        HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, HOST, RETRY_AFTER, UPGRADE,
+        WWW_AUTHENTICATE,
+    },
+    service::service_fn,
     upgrade::OnUpgrade,
-    Request, Response, StatusCode,
+    Request, Response, StatusCode, Uri, Version,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
 };
+use ipnet::IpNet;
 use regex::Regex;
-use tokio::sync::Mutex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::{oneshot, Mutex},
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
 
+use crate::access_log::{AccessEntry, AccessLog, LoggedBody};
 use crate::error::ServerError;
-use crate::state::ClientManager;
+use crate::forwarded::{client_addr, set_forwarded_headers};
+use crate::idle::copy_until_idle;
+use crate::metrics::METRICS;
+use crate::mux::MuxWaiter;
+use crate::pages::ErrorPages;
+use crate::pool::{release_when_ready, Http1Sender, SharedSenderPool};
+use crate::proxy_protocol;
+use crate::state::{Client, ClientManager, Limits, TunnelStream, TunnelType, Upstream};
+use crate::stats::CountedStream;
+
+/// Seconds a visitor is told to wait before retrying when no socket is available.
+const RETRY_AFTER_SECS: u64 = 1;
+/// Smallest read buffer hyper accepts, which bounds the request head.
+const MIN_HEADER_SIZE: usize = 8 * 1024;
+/// How long a trusted proxy has to send the PROXY protocol header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a visitor has to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type ProxyBody = BoxBody<Bytes, hyper::Error>;
+
+/// Shared by all visitor connections of the proxy port.
+pub struct ProxyState {
+    pub manager: Arc<Mutex<ClientManager>>,
+    pub pages: ErrorPages,
+    pub secure: bool,
+    /// Keep the forwarded headers set by a proxy in front of the server.
+    pub trust_forwarded_headers: bool,
+    pub proxy_protocol: bool,
+    /// Sources required to send the PROXY protocol header, all if empty.
+    pub proxy_protocol_trusted: Vec<IpNet>,
+    /// Terminate TLS on the proxy port, visitors connect with plain http if `None`.
+    pub tls: Option<TlsAcceptor>,
+    pub access_log: Option<AccessLog>,
+    /// Server-wide limits, the header limits apply before the tunnel is known.
+    pub limits: Limits,
+}
+
+impl ProxyState {
+    /// The visitor address of a new connection, read from the PROXY protocol
+    /// header if the peer is a trusted proxy.
+    pub async fn visitor_addr(
+        &self,
+        stream: &mut TcpStream,
+        peer: SocketAddr,
+    ) -> io::Result<SocketAddr> {
+        let trusted = self.proxy_protocol_trusted.is_empty()
+            || self
+                .proxy_protocol_trusted
+                .iter()
+                .any(|net| net.contains(&peer.ip()));
+        if !self.proxy_protocol || !trusted {
+            return Ok(peer);
+        }
+
+        let source = timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(stream))
+            .await
+            .map_err(|_| {
+                io::Error::new(io::ErrorKind::TimedOut, "PROXY protocol header timeout")
+            })??;
+        Ok(source.unwrap_or(peer))
+    }
+}
+
+/// Serve a visitor connection accepted on the proxy port.
+pub async fn serve_connection(mut stream: TcpStream, peer: SocketAddr, state: Arc<ProxyState>) {
+    let peer = match state.visitor_addr(&mut stream, peer).await {
+        Ok(peer) => peer,
+        Err(err) => {
+            log::warn!("Rejected proxy connection from {}: {}", peer, err);
+            return;
+        }
+    };
+    log::info!("Accepted a new proxy request from {}", peer);
+
+    match state.tls.clone() {
+        Some(acceptor) => match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => serve_http(stream, peer, state).await,
+            Ok(Err(err)) => log::warn!("TLS handshake with {} failed: {}", peer, err),
+            Err(_) => log::warn!("TLS handshake with {} timed out", peer),
+        },
+        None => serve_http(stream, peer, state).await,
+    }
+}
+
+async fn serve_http<S>(stream: S, peer: SocketAddr, state: Arc<ProxyState>)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let limits = state.limits;
+    let service = service_fn(move |req| proxy_service(req, peer, state.clone()));
+    // HTTP/1.1 or HTTP/2, told apart by the connection preface
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(limits.header_read_timeout)
+        .max_buf_size(limits.max_header_size.max(MIN_HEADER_SIZE));
+    builder
+        .http2()
+        .max_header_list_size(u32::try_from(limits.max_header_size).unwrap_or(u32::MAX));
+    if let Err(err) = builder
+        .serve_connection_with_upgrades(TokioIo::new(stream), service)
+        .await
+    {
+        log::error!("Failed to serve connection: {:?}", err);
+    }
+}
+
+/// Result of checking out a stream to the tunnel client.
+enum Checkout {
+    Ready(TunnelStream),
+    Queued(oneshot::Receiver<TcpStream>),
+    /// streams are opened on demand, up to the client's `max_sockets`
+    Multiplexed(MuxWaiter),
+    QueueFull,
+}
+
+/// Check out a stream to the tunnel client, queueing for the next socket
+/// while all of them are busy.
+pub async fn checkout_stream(
+    client: &Mutex<Client>,
+    endpoint: &str,
+    queue_timeout: Duration,
+) -> Result<CountedStream<TunnelStream>, ServerError> {
+    let started = Instant::now();
+    let (checkout, bytes_transferred) = {
+        let mut client = client.lock().await;
+        let checkout = match client.mux.clone() {
+            Some(mux) => {
+                client.touch();
+                match mux.wait() {
+                    Some(waiter) => Checkout::Multiplexed(waiter),
+                    None => Checkout::QueueFull,
+                }
+            }
+            None => match client.take().await {
+                Some(socket) => Checkout::Ready(Box::new(socket)),
+                None => match client.wait().await {
+                    Some(waiter) => Checkout::Queued(waiter),
+                    None => Checkout::QueueFull,
+                },
+            },
+        };
+        (checkout, client.bytes_transferred.clone())
+    };
+
+    // wait for the next socket without holding any lock
+    let stream = match checkout {
+        Checkout::Ready(stream) => stream,
+        Checkout::Queued(waiter) => match timeout(queue_timeout, waiter).await {
+            Ok(Ok(socket)) => Box::new(socket) as TunnelStream,
+            _ => {
+                METRICS.socket_wait.observe(started.elapsed().as_secs_f64());
+                log::warn!("No socket available for {endpoint} within {queue_timeout:?}");
+                return Err(ServerError::NoConnectionAvailable);
+            }
+        },
+        Checkout::Multiplexed(waiter) => match timeout(queue_timeout, waiter.open()).await {
+            Ok(Some(stream)) => Box::new(stream) as TunnelStream,
+            Ok(None) => return Err(ServerError::EmptyConnection),
+            Err(_) => {
+                METRICS.socket_wait.observe(started.elapsed().as_secs_f64());
+                log::warn!("No stream available for {endpoint} within {queue_timeout:?}");
+                return Err(ServerError::NoConnectionAvailable);
+            }
+        },
+        Checkout::QueueFull => return Err(ServerError::NoConnectionAvailable),
+    };
+    METRICS.socket_wait.observe(started.elapsed().as_secs_f64());
+    Ok(CountedStream::new(stream, bytes_transferred))
+}
+
+/// Serve a visitor request, failures are turned into error pages.
+pub async fn proxy_service(
+    req: Request<Incoming>,
+    peer: SocketAddr,
+    state: Arc<ProxyState>,
+) -> Result<Response<ProxyBody>, Infallible> {
+    let started = Instant::now();
+    let host = request_host(&req).map(str::to_string);
+    let entry = state.access_log.as_ref().map(|access_log| {
+        let visitor = client_addr(req.headers(), peer.ip(), state.trust_forwarded_headers);
+        let subdomain = host.as_deref().and_then(|host| extract(host).ok());
+        (
+            access_log.clone(),
+            AccessEntry::new(&req, visitor, subdomain),
+        )
+    });
+
+    let response = match proxy_handler(req, peer, &state).await {
+        Ok(response) => response,
+        Err(err) => {
+            log::warn!("Failed to proxy request to {:?}: {:?}", host, err);
+            METRICS.observe_error(&err);
+            error_response(&err, &state.pages, host.as_deref())
+        }
+    };
+    METRICS.observe_response(response.status().as_u16());
+    METRICS
+        .request_duration
+        .observe(started.elapsed().as_secs_f64());
+
+    Ok(match entry {
+        Some((access_log, mut entry)) => {
+            entry.status = response.status().as_u16();
+            response.map(|body| LoggedBody::new(body, access_log, entry, started).boxed())
+        }
+        None => response,
+    })
+}
 
 /// Reverse proxy handler
 pub async fn proxy_handler(
     mut req: Request<Incoming>,
-    manager: Arc<Mutex<ClientManager>>,
-) -> Result<Response<Incoming>> {
-    let host_header = req.headers().get(HOST).ok_or(ServerError::NoHostHeader)?;
-    let hostname = host_header.to_str()?;
+    peer: SocketAddr,
+    state: &ProxyState,
+) -> Result<Response<ProxyBody>> {
+    if req.uri().authority().is_none() && !req.headers().contains_key(HOST) {
+        return Err(ServerError::NoHostHeader.into());
+    }
+    let hostname = request_host(&req)
+        .ok_or(ServerError::InvalidHostName)?
+        .to_string();
     log::debug!("Request hostname: {}", hostname);
 
-    let endpoint = extract(hostname)?;
+    let endpoint = extract(&hostname)?;
 
-    let client_stream = {
-        let mut manager = manager.lock().await;
+    let (client, senders, queue_timeout, upstream, limits) = {
+        let manager = state.manager.lock().await;
         let client = manager
             .clients
-            .get_mut(&endpoint)
+            .get(&endpoint)
             .ok_or(ServerError::ProxyNotReady)?;
-        let mut client = client.lock().await;
-        client.take().await.ok_or(ServerError::EmptyConnection)?
+        let (senders, upstream, limits) = {
+            let mut client = client.lock().await;
+            client.touch();
+            // tcp tunnels are only reachable on their public port
+            if client.tunnel_type != TunnelType::Http {
+                return Err(ServerError::ProxyNotReady.into());
+            }
+            if let Some(ip_filter) = &client.ip_filter {
+                let visitor = client_addr(req.headers(), peer.ip(), state.trust_forwarded_headers);
+                if !ip_filter.permits(visitor) {
+                    log::info!("Refused visitor {visitor} of {endpoint}");
+                    return Err(ServerError::Forbidden.into());
+                }
+            }
+            if let Some(visitor_auth) = &client.visitor_auth {
+                let authorization = req
+                    .headers()
+                    .get(AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok());
+                if !visitor_auth.authorize(authorization) {
+                    return Err(ServerError::Unauthorized {
+                        challenge: visitor_auth.challenge(),
+                    }
+                    .into());
+                }
+                // the credential is meant for the tunnel, not the local server
+                req.headers_mut().remove(AUTHORIZATION);
+            }
+            (client.senders.clone(), client.upstream, client.limits)
+        };
+        (
+            client.clone(),
+            senders,
+            manager.queue_timeout,
+            upstream,
+            limits,
+        )
     };
-    let client_stream = hyper_util::rt::TokioIo::new(client_stream);
 
-    if !req.headers().contains_key(UPGRADE) {
-        let (mut sender, conn) = hyper::client::conn::http1::handshake(client_stream).await?;
-        tokio::spawn(async move {
-            if let Err(err) = conn.await {
-                log::error!("Connection failed: {:?}", err);
+    // refuse declared bodies early, chunked ones fail once they grow too large
+    let content_length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if content_length.is_some_and(|len| len > limits.max_body_size) {
+        return Err(ServerError::PayloadTooLarge.into());
+    }
+    let max_body_size = usize::try_from(limits.max_body_size).unwrap_or(usize::MAX);
+    let mut req = req.map(|body| Limited::new(body, max_body_size));
+
+    let proto = if state.secure { "https" } else { "http" };
+    set_forwarded_headers(
+        req.headers_mut(),
+        peer.ip(),
+        proto,
+        &hostname,
+        state.trust_forwarded_headers,
+    );
+    if upstream == Upstream::H2 {
+        to_http2(&mut req, &hostname)?;
+        // all requests are multiplexed over one connection
+        let pooled = senders.lock().unwrap().http2();
+        let mut sender = match pooled {
+            Some(sender) => sender,
+            None => {
+                let client_stream = checkout_stream(&client, &endpoint, queue_timeout).await?;
+                let (sender, conn) = hyper::client::conn::http2::handshake(
+                    TokioExecutor::new(),
+                    TokioIo::new(client_stream),
+                )
+                .await?;
+                tokio::spawn(async move {
+                    if let Err(err) = conn.await {
+                        log::error!("Connection failed: {:?}", err);
+                    }
+                });
+                senders.lock().unwrap().set_http2(sender.clone());
+                sender
             }
-        });
+        };
 
-        let response = sender.send_request(req).await?;
-        Ok(response)
+        let response = send_upstream(sender.send_request(req), limits.upstream_timeout).await?;
+        return Ok(response.map(|body| body.boxed()));
+    }
+
+    to_http1(&mut req, &hostname)?;
+    if !req.headers().contains_key(UPGRADE) {
+        let mut sender = http1_sender(&client, &senders, &endpoint, queue_timeout).await?;
+        let response = send_upstream(sender.send_request(req), limits.upstream_timeout).await?;
+        release_when_ready(senders, sender);
+        Ok(response.map(|body| body.boxed()))
     } else {
+        // the upgraded connection is handed to the visitor, it's never pooled
+        let client_stream = checkout_stream(&client, &endpoint, queue_timeout).await?;
+        let client_stream = TokioIo::new(client_stream);
         let (mut sender, conn) = hyper::client::conn::http1::handshake(client_stream).await?;
         let conn = conn.with_upgrades();
         tokio::spawn(async move {
@@ -65,7 +386,7 @@ pub async fn proxy_handler(
             .remove::<OnUpgrade>()
             .ok_or(ServerError::NoUpgradeExtension)?;
 
-        let mut response = sender.send_request(req).await?;
+        let mut response = send_upstream(sender.send_request(req), limits.upstream_timeout).await?;
 
         if response.status() == StatusCode::SWITCHING_PROTOCOLS {
             let response_upgrade_type = response
@@ -82,37 +403,162 @@ pub async fn proxy_handler(
                     .await?;
 
                 log::info!("Responding to a connection upgrade response");
+                METRICS.upgrades.inc();
 
                 tokio::spawn(async move {
                     match request_upgraded.await {
                         Ok(request_upgraded) => {
-                            let mut response_upgraded =
-                                hyper_util::rt::TokioIo::new(response_upgraded);
-                            let mut request_upgraded =
-                                hyper_util::rt::TokioIo::new(request_upgraded);
-                            if let Err(err) = tokio::io::copy_bidirectional(
-                                &mut response_upgraded,
-                                &mut request_upgraded,
+                            match copy_until_idle(
+                                TokioIo::new(response_upgraded),
+                                TokioIo::new(request_upgraded),
+                                limits.upgrade_idle_timeout,
                             )
                             .await
                             {
-                                log::error!(
+                                Ok(Some(_)) => (),
+                                Ok(None) => log::info!("Closed an idle upgraded connection"),
+                                Err(err) => log::error!(
                                     "Coping between upgraded connections failed: {:?}",
                                     err
-                                );
+                                ),
                             }
                         }
                         Err(err) => log::error!("Failed to upgrade request: {:?}", err),
                     }
                 });
             }
-            Ok(response)
+            Ok(response.map(|body| body.boxed()))
         } else {
-            Ok(response)
+            Ok(response.map(|body| body.boxed()))
+        }
+    }
+}
+
+/// Wait at most `upstream_timeout` for the response headers from the tunnel client.
+async fn send_upstream(
+    response: impl Future<Output = hyper::Result<Response<Incoming>>>,
+    upstream_timeout: Duration,
+) -> Result<Response<Incoming>> {
+    match timeout(upstream_timeout, response).await {
+        Ok(Ok(response)) => Ok(response),
+        Ok(Err(err)) if body_too_large(&err) => Err(ServerError::PayloadTooLarge.into()),
+        Ok(Err(err)) => Err(err.into()),
+        Err(_) => Err(ServerError::UpstreamTimeout.into()),
+    }
+}
+
+/// The request failed as its body grew over the limit.
+fn body_too_large(err: &hyper::Error) -> bool {
+    err.source()
+        .is_some_and(|source| source.is::<LengthLimitError>())
+}
+
+/// An idle pooled connection to the tunnel client, otherwise the first of a new
+/// socket or a pooled connection released by another request.
+async fn http1_sender(
+    client: &Mutex<Client>,
+    senders: &SharedSenderPool,
+    endpoint: &str,
+    queue_timeout: Duration,
+) -> Result<Http1Sender> {
+    let released = match senders.lock().unwrap().checkout() {
+        Ok(sender) => {
+            log::debug!("Reuse a pooled connection to {endpoint}");
+            return Ok(sender);
+        }
+        Err(released) => released,
+    };
+
+    tokio::select! {
+        biased;
+        Ok(sender) = released => Ok(sender),
+        client_stream = checkout_stream(client, endpoint, queue_timeout) => {
+            let (sender, conn) =
+                hyper::client::conn::http1::handshake(TokioIo::new(client_stream?)).await?;
+            tokio::spawn(async move {
+                if let Err(err) = conn.await {
+                    log::error!("Connection failed: {:?}", err);
+                }
+            });
+            Ok(sender)
         }
     }
 }
 
+/// Error page for visitors, any failure talking to the tunnel client is a 502.
+fn error_response(
+    err: &anyhow::Error,
+    pages: &ErrorPages,
+    host: Option<&str>,
+) -> Response<ProxyBody> {
+    let status = err
+        .downcast_ref::<ServerError>()
+        .map(ServerError::status_code)
+        .unwrap_or(StatusCode::BAD_GATEWAY);
+
+    let body = Full::new(Bytes::from(pages.render(status, host)))
+        .map_err(|never| match never {})
+        .boxed();
+
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    if let Some(ServerError::Unauthorized { challenge }) = err.downcast_ref::<ServerError>() {
+        if let Ok(challenge) = HeaderValue::from_str(challenge) {
+            response.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+        }
+    }
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, RETRY_AFTER_SECS.into());
+    }
+    response
+}
+
+/// Host of the request, the `:authority` of HTTP/2 requests or the `Host` header.
+fn request_host<B>(req: &Request<B>) -> Option<&str> {
+    match req.uri().authority() {
+        Some(authority) => Some(authority.as_str()),
+        None => req.headers().get(HOST)?.to_str().ok(),
+    }
+}
+
+/// Requests of HTTP/2 visitors are sent to HTTP/1.1 tunnels with
+/// a `Host` header and the path as target.
+fn to_http1<B>(req: &mut Request<B>, hostname: &str) -> Result<()> {
+    if req.version() != Version::HTTP_2 {
+        return Ok(());
+    }
+
+    *req.version_mut() = Version::HTTP_11;
+    if !req.headers().contains_key(HOST) {
+        req.headers_mut()
+            .insert(HOST, HeaderValue::from_str(hostname)?);
+    }
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str())
+        .parse::<Uri>()?;
+    *req.uri_mut() = path;
+    Ok(())
+}
+
+/// Requests are sent to HTTP/2 tunnels with the host as `:authority`.
+fn to_http2<B>(req: &mut Request<B>, hostname: &str) -> Result<()> {
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    let uri = format!("http://{hostname}{path}").parse::<Uri>()?;
+
+    *req.version_mut() = Version::HTTP_2;
+    *req.uri_mut() = uri;
+    req.headers_mut().remove(HOST);
+    Ok(())
+}
+
 fn extract(hostname: &str) -> Result<String> {
     let re = Regex::new(r"(https?|wss?)://")?;
     let hostname = re.replace_all(hostname, "");
@@ -127,7 +573,13 @@ fn extract(hostname: &str) -> Result<String> {
 
 #[cfg(test)]
 mod tests {
-    use super::extract;
+    use http_body_util::Empty;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
 
     #[test]
     fn extract_subdomain_works() {
@@ -146,4 +598,125 @@ mod tests {
             assert_eq!(extract(domain).unwrap(), subdomain);
         }
     }
+
+    #[test]
+    fn requests_are_translated_for_the_upstream() {
+        let h2_request = || {
+            Request::builder()
+                .version(Version::HTTP_2)
+                .uri("https://demo.example.org/path?q=1")
+                .body(())
+                .unwrap()
+        };
+
+        let mut req = h2_request();
+        assert_eq!(request_host(&req), Some("demo.example.org"));
+        to_http1(&mut req, "demo.example.org").unwrap();
+        assert_eq!(req.version(), Version::HTTP_11);
+        assert_eq!(req.uri(), "/path?q=1");
+        assert_eq!(req.headers()[HOST], "demo.example.org");
+
+        let mut req = Request::builder()
+            .uri("/path")
+            .header(HOST, "demo.example.org:8080")
+            .body(())
+            .unwrap();
+        assert_eq!(request_host(&req), Some("demo.example.org:8080"));
+        to_http2(&mut req, "demo.example.org:8080").unwrap();
+        assert_eq!(req.version(), Version::HTTP_2);
+        assert_eq!(req.uri(), "http://demo.example.org:8080/path");
+        assert!(!req.headers().contains_key(HOST));
+    }
+
+    #[test]
+    fn error_response_status_works() {
+        let pages = ErrorPages::default();
+        let cases = [
+            (ServerError::NoHostHeader, StatusCode::BAD_REQUEST),
+            (ServerError::ProxyNotReady, StatusCode::NOT_FOUND),
+            (ServerError::EmptyConnection, StatusCode::BAD_GATEWAY),
+            (ServerError::UpstreamTimeout, StatusCode::GATEWAY_TIMEOUT),
+            (ServerError::PayloadTooLarge, StatusCode::PAYLOAD_TOO_LARGE),
+        ];
+
+        for (err, status) in cases {
+            let response = error_response(&err.into(), &pages, None);
+            assert_eq!(response.status(), status);
+        }
+
+        let response = error_response(&ServerError::NoConnectionAvailable.into(), &pages, None);
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+
+        let response = error_response(&anyhow::anyhow!("connection reset"), &pages, None);
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn http2_visitors_get_forwarded_host() {
+        // local service behind the tunnel, answering with the request head it got
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = upstream.accept().await.unwrap();
+            let mut head = vec![0u8; 4096];
+            let len = socket.read(&mut head).await.unwrap();
+            let head = String::from_utf8_lossy(&head[..len]).to_lowercase();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{head}",
+                head.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let mut manager = ClientManager::new(10);
+        manager
+            .put("demo".to_string(), Default::default())
+            .await
+            .unwrap();
+        let client = manager.clients["demo"].clone();
+        let socket = TcpStream::connect(addr).await.unwrap();
+        client
+            .lock()
+            .await
+            .available_sockets
+            .lock()
+            .await
+            .sockets
+            .push(socket);
+
+        let state = Arc::new(ProxyState {
+            manager: Arc::new(Mutex::new(manager)),
+            pages: ErrorPages::default(),
+            secure: false,
+            trust_forwarded_headers: false,
+            proxy_protocol: false,
+            proxy_protocol_trusted: vec![],
+            tls: None,
+            access_log: None,
+            limits: Limits::default(),
+        });
+        let (visitor, server) = tokio::io::duplex(64 * 1024);
+        let peer = "203.0.113.7:4000".parse().unwrap();
+        tokio::spawn(serve_http(server, peer, state));
+
+        let (mut sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(visitor))
+                .await
+                .unwrap();
+        tokio::spawn(conn);
+        let req = Request::builder()
+            .version(Version::HTTP_2)
+            .uri("http://demo.localhost/path")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let response = sender.send_request(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let head = response.into_body().collect().await.unwrap().to_bytes();
+        let head = String::from_utf8_lossy(&head);
+        assert!(head.starts_with("get /path http/1.1"));
+        assert!(head.contains("x-forwarded-host: demo.localhost"));
+        assert!(head.contains("host=\"demo.localhost\""));
+    }
 }
@@ -0,0 +1,150 @@
+//! HAProxy PROXY protocol v1 and v2, used by a load balancer in front of the
+//! proxy port to pass on the original visitor address.
+//! See https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest v1 header including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Read the PROXY protocol header at the start of the stream, without
+/// consuming any byte after it.
+///
+/// Returns the source address, `None` if the sender doesn't forward one,
+/// e.g. health checks with `UNKNOWN` or `LOCAL`.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    // both the v2 signature and the shortest v1 header are at least 12 bytes
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(V1_PREFIX) {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    start: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY protocol v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol v1 header is not utf8"))?;
+    let parts = line.split(' ').collect::<Vec<_>>();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            let ip = source
+                .parse::<IpAddr>()
+                .map_err(|_| invalid("invalid PROXY protocol v1 source address"))?;
+            let port = source_port
+                .parse::<u16>()
+                .map_err(|_| invalid("invalid PROXY protocol v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY protocol v1 header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version_command, family, len_high, len_low] = header;
+
+    let mut addresses = vec![0u8; u16::from_be_bytes([len_high, len_low]) as usize];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL, the connection was made by the proxy itself
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol v2 command")),
+    }
+
+    match family >> 4 {
+        // AF_INET
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        2 if addresses.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        // AF_UNSPEC, AF_UNIX
+        0 | 3 => Ok(None),
+        _ => Err(invalid("malformed PROXY protocol v2 addresses")),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn parse(mut data: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let result = read_header(&mut data).await;
+        (result, data.to_vec())
+    }
+
+    #[tokio::test]
+    async fn read_v1_works() {
+        let (addr, rest) = parse(b"PROXY TCP4 1.2.3.4 10.0.0.1 56324 443\r\nGET /").await;
+        assert_eq!(addr.unwrap(), Some("1.2.3.4:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (addr, _) = parse(b"PROXY TCP6 ::1 ::2 4000 443\r\n").await;
+        assert_eq!(addr.unwrap(), Some("[::1]:4000".parse().unwrap()));
+
+        let (addr, _) = parse(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(addr.unwrap(), None);
+
+        assert!(parse(b"PROXY TCP4 1.2.3.4\r\n").await.0.is_err());
+        assert!(parse(b"GET / HTTP/1.1\r\n").await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_v2_works() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend([0x21, 0x11, 0, 12]);
+        data.extend([1, 2, 3, 4, 10, 0, 0, 1]);
+        data.extend(56324u16.to_be_bytes());
+        data.extend(443u16.to_be_bytes());
+        data.extend(b"GET /");
+
+        let (addr, rest) = parse(&data).await;
+        assert_eq!(addr.unwrap(), Some("1.2.3.4:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(parse(&local).await.0.unwrap(), None);
+    }
+}
@@ -1,15 +1,28 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     io,
-    sync::Arc,
-    time::{Duration, Instant},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
 use socket2::{SockRef, TcpKeepalive};
+
+use crate::auth::{constant_time_eq, Auth};
+use crate::ip_filter::IpFilter;
+use crate::mux::MuxHandle;
+use crate::pool::SharedSenderPool;
+use crate::proxy::checkout_stream;
+use crate::visitor_auth::VisitorAuth;
 use tokio::{
-    io::Interest,
+    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWrite, Interest},
     net::{TcpListener, TcpStream},
-    sync::Mutex,
+    sync::{oneshot, Mutex},
     task::JoinHandle,
     time::timeout,
 };
@@ -17,25 +30,130 @@ use tokio::{
 // See https://tldp.org/HOWTO/html_single/TCP-Keepalive-HOWTO to understand how keepalive work.
 const TCP_KEEPALIVE_TIME: Duration = Duration::from_secs(30);
 const TCP_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
-#[cfg(not(target_os = "windows"))]
 const TCP_KEEPALIVE_RETRIES: u32 = 5;
 
 /// How long before an unused client is cleaned up.
 const CLEANUP_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// The longest cleanup timeout a tunnel may request.
+const MAX_CLEANUP_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+/// How often unused clients are cleaned up.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+/// How long a client listener waits for a new socket before it drops disconnected ones.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Defaults of the limits of visitor requests.
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_BODY_SIZE: u64 = 100 * 1024 * 1024;
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(60);
+const UPGRADE_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// How long a new client socket has to send its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Length of the secret a client has to present on each socket.
+const SECRET_LEN: usize = 32;
+
+/// Defaults of the visitor request queue.
+pub const DEFAULT_QUEUE_DEPTH: usize = 32;
+pub const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Chars and length of the subdomain assigned to `/?new` requests.
+const RANDOM_ENDPOINT_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+const RANDOM_ENDPOINT_LEN: usize = 10;
+
+/// A stream to the tunnel client, either a tcp socket or a multiplexed stream.
+pub trait TunnelIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> TunnelIo for T {}
+
+pub type TunnelStream = Box<dyn TunnelIo>;
+
+/// Timings of the cleanup of unused clients and the health checks of their sockets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timings {
+    /// How long before an unused client is cleaned up.
+    pub cleanup_timeout: Duration,
+    /// The longest cleanup timeout a tunnel may request at registration.
+    pub max_cleanup_timeout: Duration,
+    /// How often unused clients are cleaned up.
+    pub cleanup_interval: Duration,
+    /// How long a client listener waits for a new socket before it drops disconnected ones.
+    pub accept_timeout: Duration,
+    /// Idle time of a client socket before TCP keepalive probes are sent.
+    pub keepalive_time: Duration,
+    pub keepalive_interval: Duration,
+    /// Unanswered probes before a client socket is closed, ignored on Windows.
+    pub keepalive_retries: u32,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Timings {
+            cleanup_timeout: CLEANUP_TIMEOUT,
+            max_cleanup_timeout: MAX_CLEANUP_TIMEOUT,
+            cleanup_interval: CLEANUP_INTERVAL,
+            accept_timeout: ACCEPT_TIMEOUT,
+            keepalive_time: TCP_KEEPALIVE_TIME,
+            keepalive_interval: TCP_KEEPALIVE_INTERVAL,
+            keepalive_retries: TCP_KEEPALIVE_RETRIES,
+        }
+    }
+}
+
+/// Limits of visitor requests. The header limits apply to all visitors, the
+/// others are the defaults and the maximum a tunnel may request at registration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Bytes of the request head of HTTP/1.1 visitors, at least 8 KiB,
+    /// and of the header list of HTTP/2 visitors.
+    pub max_header_size: usize,
+    /// How long an HTTP/1.1 visitor has to send the request head.
+    pub header_read_timeout: Duration,
+    /// Bytes of a request body, larger requests get a 413.
+    pub max_body_size: u64,
+    /// How long to wait for the response headers from the tunnel client.
+    pub upstream_timeout: Duration,
+    /// How long an upgraded connection, e.g. a WebSocket, may be idle before it's closed.
+    pub upgrade_idle_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_header_size: MAX_HEADER_SIZE,
+            header_read_timeout: HEADER_READ_TIMEOUT,
+            max_body_size: MAX_BODY_SIZE,
+            upstream_timeout: UPSTREAM_TIMEOUT,
+            upgrade_idle_timeout: UPGRADE_IDLE_TIMEOUT,
+        }
+    }
+}
 
 /// App state holds all the client connection and status info.
 pub struct State {
     pub manager: Arc<Mutex<ClientManager>>,
-    pub max_sockets: u8,
     pub require_auth: bool,
+    pub auth: Box<dyn Auth + Send + Sync>,
+    /// Only accept credentials sent in the `Authorization` header.
+    pub reject_query_credential: bool,
     pub secure: bool,
     pub domain: String,
+    /// Port accepting multiplexed client connections, if enabled
+    pub mux_port: Option<u16>,
+    /// When the server was started
+    pub started_at: Instant,
 }
 
 pub struct ClientManager {
     pub clients: HashMap<String, Arc<Mutex<Client>>>,
     pub _tunnels: u16,
     pub default_max_sockets: u8,
+    /// How many visitor requests each client queues while waiting for a socket.
+    pub queue_depth: usize,
+    /// How long a queued visitor request waits for a socket.
+    pub queue_timeout: Duration,
+    pub timings: Timings,
+    pub limits: Limits,
 }
 
 impl ClientManager {
@@ -44,15 +162,120 @@ impl ClientManager {
             clients: HashMap::new(),
             _tunnels: 0,
             default_max_sockets: max_sockets,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            queue_timeout: DEFAULT_QUEUE_TIMEOUT,
+            timings: Timings::default(),
+            limits: Limits::default(),
         }
     }
 
-    pub async fn put(&mut self, url: String) -> io::Result<u16> {
-        let client = Arc::new(Mutex::new(Client::new(self.default_max_sockets)));
-        self.clients.insert(url, client.clone());
+    /// Add a new client for the url.
+    /// Multiplexed clients don't listen on a port of their own.
+    pub async fn put(&mut self, url: String, options: TunnelOptions) -> io::Result<Registration> {
+        let max_sockets = options.max_sockets.map_or(self.default_max_sockets, |max| {
+            max.min(self.default_max_sockets)
+        });
+        let client = Arc::new(Mutex::new(Client::new(max_sockets, self.queue_depth)));
+        let previous = self.clients.insert(url.clone(), client.clone());
+
+        let public_port = match options.tunnel_type {
+            TunnelType::Http => None,
+            TunnelType::Tcp => {
+                // the same owner reconnecting keeps the advertised port
+                let listener = match previous {
+                    Some(previous) => previous
+                        .lock()
+                        .await
+                        .take_public_listener(options.owner.as_deref()),
+                    None => None,
+                };
+                let client = Arc::downgrade(&client);
+                Some(listen_public(url, client, self.queue_timeout, listener).await?)
+            }
+        };
 
         let mut client = client.lock().await;
-        client.listen().await
+        client.limits = Limits {
+            max_body_size: at_most(options.max_body_size, self.limits.max_body_size),
+            upstream_timeout: at_most(options.upstream_timeout, self.limits.upstream_timeout),
+            upgrade_idle_timeout: at_most(
+                options.upgrade_idle_timeout,
+                self.limits.upgrade_idle_timeout,
+            ),
+            ..self.limits
+        };
+        client.timings = Timings {
+            cleanup_timeout: options
+                .cleanup_timeout
+                .map_or(self.timings.cleanup_timeout, |requested| {
+                    requested.min(self.timings.max_cleanup_timeout)
+                }),
+            ..self.timings
+        };
+        client.handshake = options.handshake;
+        let port = match options.transport {
+            Transport::Sockets => Some(client.listen().await?),
+            Transport::Multiplex => None,
+        };
+        client.transport = options.transport;
+        client.tunnel_type = options.tunnel_type;
+        client.upstream = options.upstream;
+        client.visitor_auth = options.visitor_auth;
+        client.ip_filter = options.ip_filter;
+        client.owner = options.owner;
+        Ok(Registration {
+            port,
+            public_port,
+            max_sockets,
+            secret: client.secret.clone(),
+            cleanup_timeout: client.timings.cleanup_timeout,
+            limits: client.limits,
+        })
+    }
+
+    /// If the url may be registered by the claimant: it's free or expired, the claimant
+    /// owns it by the same credential hash, or presents the secret of the current client.
+    pub async fn can_claim(&self, url: &str, owner: Option<&str>, secret: Option<&str>) -> bool {
+        let Some(client) = self.clients.get(url) else {
+            return true;
+        };
+        let client = client.lock().await;
+        if client.should_cleanup().await {
+            return true;
+        }
+
+        let owned = matches!(
+            (client.owner.as_deref(), owner),
+            (Some(current), Some(owner)) if constant_time_eq(current.as_bytes(), owner.as_bytes())
+        );
+        owned
+            || secret
+                .is_some_and(|secret| constant_time_eq(client.secret.as_bytes(), secret.as_bytes()))
+    }
+
+    /// Generate a random endpoint that is not used by any existing client.
+    pub fn random_endpoint(&self) -> String {
+        let mut rng = rand::thread_rng();
+        loop {
+            let endpoint: String = (0..RANDOM_ENDPOINT_LEN)
+                .map(|_| {
+                    RANDOM_ENDPOINT_CHARS[rng.gen_range(0..RANDOM_ENDPOINT_CHARS.len())] as char
+                })
+                .collect();
+
+            if !self.clients.contains_key(&endpoint) {
+                return endpoint;
+            }
+        }
+    }
+
+    /// Total number of sockets and connections of all clients, as in their status.
+    pub async fn sockets_count(&self) -> usize {
+        let mut count = 0;
+        for client in self.clients.values() {
+            count += client.lock().await.connected_sockets().await;
+        }
+        count
     }
 
     /// clean up old unused clients
@@ -73,23 +296,161 @@ impl ClientManager {
     }
 }
 
+/// How the tunnel client connects to the server.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// Each visitor connection takes one of the tcp sockets opened to the client's port.
+    #[default]
+    Sockets,
+    /// Visitor streams are multiplexed over a single connection to the mux port.
+    Multiplex,
+}
+
+/// How visitors reach the tunnel.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelType {
+    /// Requests to `<endpoint>.<domain>` on the proxy port, routed by the Host header.
+    #[default]
+    Http,
+    /// Any connection to a public port allocated for the tunnel, piped as is.
+    Tcp,
+}
+
+impl FromStr for TunnelType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http" => Ok(TunnelType::Http),
+            "tcp" => Ok(TunnelType::Tcp),
+            _ => Err(format!("unknown tunnel type {s}, expected http or tcp")),
+        }
+    }
+}
+
+/// Protocol the local service of a http tunnel speaks.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Upstream {
+    /// Requests of HTTP/1.1 and HTTP/2 visitors are sent as HTTP/1.1.
+    #[default]
+    Http1,
+    /// Requests are sent as HTTP/2 with prior knowledge (h2c), e.g. for gRPC.
+    H2,
+}
+
+/// Options a client registers its tunnel with.
+#[derive(Debug, Default, Clone)]
+pub struct TunnelOptions {
+    pub transport: Transport,
+    pub tunnel_type: TunnelType,
+    pub upstream: Upstream,
+    /// Sockets the client may open, capped by the server's maximum.
+    pub max_sockets: Option<u8>,
+    /// Credentials required from visitors, public if `None`.
+    pub visitor_auth: Option<VisitorAuth>,
+    /// Addresses allowed or denied to visit, all allowed if `None`.
+    pub ip_filter: Option<IpFilter>,
+    /// Hash of the credential the tunnel is registered with, which keeps the url.
+    pub owner: Option<String>,
+    /// How long the tunnel is kept unused, capped by the server's maximum.
+    pub cleanup_timeout: Option<Duration>,
+    /// Sockets start with the registration secret and a newline, opted in by the
+    /// client as the upstream Node client doesn't send it.
+    pub handshake: bool,
+    /// Limits of visitor requests, capped by the server's.
+    pub max_body_size: Option<u64>,
+    pub upstream_timeout: Option<Duration>,
+    pub upgrade_idle_timeout: Option<Duration>,
+}
+
+/// What a tunnel client needs to connect after registering.
+pub struct Registration {
+    /// Port the client opens its sockets to, `None` for multiplexed clients.
+    pub port: Option<u16>,
+    /// Port visitors connect to for tcp tunnels.
+    pub public_port: Option<u16>,
+    pub max_sockets: u8,
+    /// Secret the client must present on every socket.
+    pub secret: String,
+    pub cleanup_timeout: Duration,
+    pub limits: Limits,
+}
+
+/// Sockets ready to proxy, and visitor requests queued for the next socket.
+#[derive(Default)]
+pub struct SocketPool {
+    pub sockets: Vec<TcpStream>,
+    waiters: VecDeque<oneshot::Sender<TcpStream>>,
+}
+
 pub struct Client {
-    pub available_sockets: Arc<Mutex<Vec<TcpStream>>>,
+    pub available_sockets: Arc<Mutex<SocketPool>>,
     pub port: Option<u16>,
     pub max_sockets: u8,
+    pub queue_depth: usize,
     listen_task: Option<JoinHandle<()>>,
     /// last time a new connection was established
     last_connection_time: Instant,
+    /// bytes proxied through the sockets of this client in both directions
+    pub bytes_transferred: Arc<AtomicU64>,
+    /// secret proving the client when it re-registers or closes the tunnel
+    pub secret: String,
+    /// sockets must start with the secret before they're pooled
+    pub handshake: bool,
+    /// control connection of a multiplexed client
+    pub mux: Option<MuxHandle>,
+    /// only multiplexed clients may attach a control connection
+    pub transport: Transport,
+    /// http connections over the sockets, reused across visitor requests
+    pub senders: SharedSenderPool,
+    pub tunnel_type: TunnelType,
+    pub upstream: Upstream,
+    pub visitor_auth: Option<VisitorAuth>,
+    pub ip_filter: Option<IpFilter>,
+    /// hash of the credential owning the url until the client is cleaned up
+    pub owner: Option<String>,
+    /// when the client is cleaned up and how its sockets are checked
+    pub timings: Timings,
+    /// limits of the requests of its visitors
+    pub limits: Limits,
+    /// public port of a tcp tunnel and the task accepting visitors on it
+    pub public_port: Option<u16>,
+    public_listener: Option<Arc<TcpListener>>,
+    public_task: Option<JoinHandle<()>>,
 }
 
 impl Client {
-    pub fn new(max_sockets: u8) -> Self {
+    pub fn new(max_sockets: u8, queue_depth: usize) -> Self {
         Client {
-            available_sockets: Arc::new(Mutex::new(vec![])),
+            available_sockets: Arc::new(Mutex::new(SocketPool::default())),
             port: None,
             max_sockets,
+            queue_depth,
             listen_task: None,
             last_connection_time: std::time::Instant::now(),
+            bytes_transferred: Arc::new(AtomicU64::new(0)),
+            secret: rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(SECRET_LEN)
+                .map(char::from)
+                .collect(),
+            handshake: false,
+            mux: None,
+            transport: Transport::Sockets,
+            senders: SharedSenderPool::default(),
+            tunnel_type: TunnelType::Http,
+            upstream: Upstream::Http1,
+            visitor_auth: None,
+            ip_filter: None,
+            owner: None,
+            timings: Timings::default(),
+            limits: Limits::default(),
+            public_port: None,
+            public_listener: None,
+            public_task: None,
         }
     }
 
@@ -100,42 +461,46 @@ impl Client {
 
         let sockets = self.available_sockets.clone();
         let max_sockets = self.max_sockets;
+        // clients like the upstream Node one connect without the handshake
+        let secret = self.handshake.then(|| self.secret.clone());
+        let timings = self.timings;
 
         let listen_task = tokio::spawn(async move {
-            // TODO check client is authenticated for the port
             loop {
-                match timeout(Duration::from_secs(20), listener.accept()).await {
+                match timeout(timings.accept_timeout, listener.accept()).await {
                     Ok(Ok((socket, addr))) => {
                         log::info!("new client connection: {:?}", addr);
 
-                        let mut sockets = sockets.lock().await;
-                        let sockets_len = sockets.len();
-
-                        if sockets_len < max_sockets as usize {
-                            log::debug!("Add a new socket {}/{max_sockets}", sockets_len + 1,);
-
-                            let ka = TcpKeepalive::new()
-                                .with_time(TCP_KEEPALIVE_TIME)
-                                .with_interval(TCP_KEEPALIVE_INTERVAL);
-                            #[cfg(not(target_os = "windows"))]
-                            let ka = ka.with_retries(TCP_KEEPALIVE_RETRIES);
-                            let sf = SockRef::from(&socket);
-                            if let Err(err) = sf.set_tcp_keepalive(&ka) {
-                                log::warn!("failed to enable TCP keepalive: {err}");
+                        let sockets = sockets.clone();
+                        let secret = secret.clone();
+                        // handshake in its own task so a slow peer can't block accepting
+                        tokio::spawn(async move {
+                            let mut socket = socket;
+                            let authenticated = match &secret {
+                                Some(secret) => authenticate_socket(&mut socket, secret).await,
+                                None => Ok(true),
+                            };
+                            match authenticated {
+                                Ok(true) => {
+                                    set_keepalive(&socket, &timings);
+                                    pool_socket(&sockets, socket, max_sockets).await
+                                }
+                                Ok(false) => {
+                                    log::warn!("Rejected unauthenticated client connection: {addr}")
+                                }
+                                Err(err) => log::warn!(
+                                    "Rejected client connection {addr}, handshake failed: {err}"
+                                ),
                             }
-
-                            sockets.push(socket)
-                        } else {
-                            log::warn!("Reached sockets max: {sockets_len}/{max_sockets}");
-                        }
+                        });
                     }
                     Ok(Err(e)) => log::info!("Couldn't get client: {:?}", e),
                     Err(_) => {
                         // timeout clean up timeout connections
-                        let mut sockets = sockets.lock().await;
-                        let sockets_len = sockets.len();
+                        let mut pool = sockets.lock().await;
+                        let sockets_len = pool.sockets.len();
                         let mut connected_sockets = vec![];
-                        while let Some(s) = sockets.pop() {
+                        while let Some(s) = pool.sockets.pop() {
                             if socket_is_writable(&s).await {
                                 connected_sockets.push(s);
                             }
@@ -147,7 +512,8 @@ impl Client {
                                 sockets_len - connected_sockets.len()
                             );
                         }
-                        *sockets = connected_sockets;
+                        pool.sockets = connected_sockets;
+                        pool.waiters.retain(|waiter| !waiter.is_closed());
                     }
                 }
             }
@@ -159,44 +525,249 @@ impl Client {
 
     pub async fn take(&mut self) -> Option<TcpStream> {
         self.last_connection_time = Instant::now();
-        let mut sockets = self.available_sockets.lock().await;
-
-        let sockets_len = sockets.len();
-        let mut i = sockets_len;
-        while let Some(socket) = sockets.pop() {
-            log::debug!(
-                "try using socket {i}/{sockets_len} (max: {})",
-                self.max_sockets
-            );
-
-            if socket_is_writable(&socket).await {
-                return Some(socket);
-            }
+        let mut pool = self.available_sockets.lock().await;
 
-            log::warn!(
-                "socket {} is no longer writable, discard it",
-                sockets.len() + 1
-            );
+        take_writable(&mut pool.sockets, self.max_sockets).await
+    }
 
-            i -= 1;
+    /// Mark the client as used by a request over a pooled connection.
+    pub fn touch(&mut self) {
+        self.last_connection_time = Instant::now();
+    }
+
+    /// Queue for the next socket the client connects, `None` if the queue is full.
+    pub async fn wait(&mut self) -> Option<oneshot::Receiver<TcpStream>> {
+        self.last_connection_time = Instant::now();
+        let (tx, rx) = oneshot::channel();
+        let mut pool = self.available_sockets.lock().await;
+
+        // a socket may have been pooled since the last `take`
+        if let Some(socket) = take_writable(&mut pool.sockets, self.max_sockets).await {
+            let _ = tx.send(socket);
+            return Some(rx);
+        }
+
+        pool.waiters.retain(|waiter| !waiter.is_closed());
+        if pool.waiters.len() >= self.queue_depth {
+            log::warn!("Reached queue max: {}", self.queue_depth);
+            return None;
+        }
+
+        pool.waiters.push_back(tx);
+        Some(rx)
+    }
+
+    /// Stop accepting tcp visitors and hand the public listener over to the next
+    /// registration of the url, if it's by the same owner.
+    fn take_public_listener(&mut self, owner: Option<&str>) -> Option<Arc<TcpListener>> {
+        if let Some(task) = self.public_task.take() {
+            task.abort();
+        }
+        if self.owner.as_deref() != owner {
+            return None;
+        }
+        self.public_listener.take()
+    }
+
+    fn mux_connected(&self) -> bool {
+        self.mux.as_ref().is_some_and(|mux| !mux.is_closed())
+    }
+
+    /// Idle sockets and pooled connections, and the multiplexed connection.
+    pub async fn connected_sockets(&self) -> usize {
+        self.available_sockets.lock().await.sockets.len()
+            + self.senders.lock().unwrap().idle()
+            + usize::from(self.mux_connected())
+    }
+
+    pub async fn status(&self) -> ClientStatus {
+        let last_connection_time = SystemTime::now()
+            .checked_sub(self.last_connection_time.elapsed())
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|time| time.as_secs())
+            .unwrap_or_default();
+
+        ClientStatus {
+            connected_sockets: self.connected_sockets().await,
+            max_sockets: self.max_sockets,
+            port: self.port,
+            public_port: self.public_port,
+            last_connection_time,
+            bytes_transferred: self.bytes_transferred.load(Ordering::Relaxed),
         }
-        None
     }
 
     /// If the client has not been used for a while and so should be cleaned up.
     pub async fn should_cleanup(&self) -> bool {
-        let sockets = self.available_sockets.lock().await;
+        let pool = self.available_sockets.lock().await;
 
-        sockets.is_empty() && self.last_connection_time.elapsed() > CLEANUP_TIMEOUT
+        pool.sockets.is_empty()
+            && self.senders.lock().unwrap().idle() == 0
+            && !self.mux_connected()
+            && self.last_connection_time.elapsed() > self.timings.cleanup_timeout
     }
 }
 
+/// Status snapshot of a client.
+pub struct ClientStatus {
+    pub connected_sockets: usize,
+    pub max_sockets: u8,
+    pub port: Option<u16>,
+    pub public_port: Option<u16>,
+    /// Unix timestamp in seconds
+    pub last_connection_time: u64,
+    pub bytes_transferred: u64,
+}
+
 impl Drop for Client {
     fn drop(&mut self) {
         if let Some(task) = self.listen_task.take() {
             task.abort();
         }
+        if let Some(task) = self.public_task.take() {
+            task.abort();
+        }
+    }
+}
+
+/// Accept visitors of a tcp tunnel on a new public port, or the one of its previous
+/// registration, and pipe each connection to a stream of the tunnel client.
+async fn listen_public(
+    endpoint: String,
+    client: Weak<Mutex<Client>>,
+    queue_timeout: Duration,
+    listener: Option<Arc<TcpListener>>,
+) -> io::Result<u16> {
+    let listener = match listener {
+        Some(listener) => listener,
+        None => Arc::new(TcpListener::bind("0.0.0.0:0").await?),
+    };
+    let port = listener.local_addr()?.port();
+    log::info!("Tcp tunnel {endpoint} listens at {port}");
+
+    let task_client = client.clone();
+    let task_listener = listener.clone();
+    let public_task = tokio::spawn(async move {
+        loop {
+            let (mut visitor, addr) = match task_listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    log::info!("Couldn't get tcp visitor: {:?}", err);
+                    continue;
+                }
+            };
+            // the client is gone, its drop aborts this task as well
+            let Some(client) = task_client.upgrade() else {
+                return;
+            };
+            log::info!("Accepted a new tcp connection to {endpoint} from {addr}");
+
+            let endpoint = endpoint.clone();
+            tokio::spawn(async move {
+                let permitted = match &client.lock().await.ip_filter {
+                    Some(ip_filter) => ip_filter.permits(addr.ip()),
+                    None => true,
+                };
+                if !permitted {
+                    log::info!("Refused tcp visitor {addr} of {endpoint}");
+                    return;
+                }
+
+                let mut stream = match checkout_stream(&client, &endpoint, queue_timeout).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::warn!("Failed to proxy tcp connection to {endpoint}: {err}");
+                        return;
+                    }
+                };
+                drop(client);
+                if let Err(err) = copy_bidirectional(&mut visitor, &mut stream).await {
+                    log::debug!("Tcp connection to {endpoint} closed: {err}");
+                }
+            });
+        }
+    });
+
+    if let Some(client) = client.upgrade() {
+        let mut client = client.lock().await;
+        client.public_port = Some(port);
+        client.public_listener = Some(listener);
+        client.public_task = Some(public_task);
+    }
+    Ok(port)
+}
+
+/// The requested value if there's one below the maximum.
+fn at_most<T: Ord + Copy>(requested: Option<T>, max: T) -> T {
+    requested.map_or(max, |requested| requested.min(max))
+}
+
+/// Hand an authenticated socket to the oldest queued request,
+/// or add it to the pool if it's not full yet.
+async fn pool_socket(pool: &Mutex<SocketPool>, socket: TcpStream, max_sockets: u8) {
+    let mut pool = pool.lock().await;
+    let mut socket = socket;
+    while let Some(waiter) = pool.waiters.pop_front() {
+        match waiter.send(socket) {
+            Ok(()) => {
+                log::debug!("Hand a new socket to a queued request");
+                return;
+            }
+            // the request has timed out or been canceled
+            Err(unused) => socket = unused,
+        }
+    }
+
+    let sockets_len = pool.sockets.len();
+    if sockets_len < max_sockets as usize {
+        log::debug!("Add a new socket {}/{max_sockets}", sockets_len + 1,);
+
+        pool.sockets.push(socket)
+    } else {
+        log::warn!("Reached sockets max: {sockets_len}/{max_sockets}");
+    }
+}
+
+pub fn set_keepalive(socket: &TcpStream, timings: &Timings) {
+    let ka = TcpKeepalive::new()
+        .with_time(timings.keepalive_time)
+        .with_interval(timings.keepalive_interval);
+    #[cfg(not(target_os = "windows"))]
+    let ka = ka.with_retries(timings.keepalive_retries);
+    let sf = SockRef::from(socket);
+    if let Err(err) = sf.set_tcp_keepalive(&ka) {
+        log::warn!("failed to enable TCP keepalive: {err}");
+    }
+}
+
+/// Verify the handshake sent by the tunnel client on a new socket,
+/// which is the registration secret followed by a newline.
+async fn authenticate_socket(socket: &mut TcpStream, secret: &str) -> io::Result<bool> {
+    let mut handshake = vec![0u8; secret.len() + 1];
+    timeout(HANDSHAKE_TIMEOUT, socket.read_exact(&mut handshake)).await??;
+
+    Ok(handshake.pop() == Some(b'\n') && constant_time_eq(&handshake, secret.as_bytes()))
+}
+
+/// Pop the last socket which is still writable, discarding closed ones.
+async fn take_writable(sockets: &mut Vec<TcpStream>, max_sockets: u8) -> Option<TcpStream> {
+    let sockets_len = sockets.len();
+    let mut i = sockets_len;
+    while let Some(socket) = sockets.pop() {
+        log::debug!("try using socket {i}/{sockets_len} (max: {max_sockets})");
+
+        if socket_is_writable(&socket).await {
+            return Some(socket);
+        }
+
+        log::warn!(
+            "socket {} is no longer writable, discard it",
+            sockets.len() + 1
+        );
+
+        i -= 1;
     }
+    None
 }
 
 async fn socket_is_writable(socket: &TcpStream) -> bool {
@@ -207,3 +778,217 @@ async fn socket_is_writable(socket: &TcpStream) -> bool {
         .map(|ready| !ready.is_write_closed())
         .unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    async fn handshake(payload: &[u8], secret: &str) -> bool {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(payload).await.unwrap();
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+        authenticate_socket(&mut socket, secret).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn authenticate_socket_works() {
+        let secret = "abcdef";
+
+        assert!(handshake(b"abcdef\n", secret).await);
+        assert!(!handshake(b"abcdeg\n", secret).await);
+        assert!(!handshake(b"abcdefg", secret).await);
+    }
+
+    #[tokio::test]
+    async fn queued_request_gets_next_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client_side = TcpStream::connect(addr).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let mut client = Client::new(10, 1);
+        let waiter = client.wait().await.expect("queue has room");
+        assert!(client.wait().await.is_none(), "queue should be full");
+
+        pool_socket(&client.available_sockets, socket, client.max_sockets).await;
+
+        assert!(waiter.await.is_ok());
+        assert!(client.available_sockets.lock().await.sockets.is_empty());
+    }
+
+    #[tokio::test]
+    async fn tcp_tunnel_pipes_public_connections() {
+        let mut manager = ClientManager::new(10);
+        let registration = manager
+            .put(
+                "demo".to_string(),
+                TunnelOptions {
+                    tunnel_type: TunnelType::Tcp,
+                    handshake: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        let mut tunnel = TcpStream::connect(("127.0.0.1", registration.port.unwrap()))
+            .await
+            .unwrap();
+        tunnel
+            .write_all(format!("{}\n", registration.secret).as_bytes())
+            .await
+            .unwrap();
+
+        let mut visitor = TcpStream::connect(("127.0.0.1", registration.public_port.unwrap()))
+            .await
+            .unwrap();
+        visitor.write_all(b"ping").await.unwrap();
+
+        let mut received = [0u8; 4];
+        tunnel.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+    }
+
+    #[tokio::test]
+    async fn tcp_tunnel_keeps_public_port_for_its_owner() {
+        let mut manager = ClientManager::new(10);
+        let options = |owner: &str| TunnelOptions {
+            tunnel_type: TunnelType::Tcp,
+            owner: Some(owner.to_string()),
+            ..Default::default()
+        };
+        let first = manager
+            .put("demo".to_string(), options("kai"))
+            .await
+            .unwrap();
+        let second = manager
+            .put("demo".to_string(), options("kai"))
+            .await
+            .unwrap();
+        assert_eq!(first.public_port, second.public_port);
+
+        // visitors of the kept port reach the new registration
+        let mut tunnel = TcpStream::connect(("127.0.0.1", second.port.unwrap()))
+            .await
+            .unwrap();
+        let mut visitor = TcpStream::connect(("127.0.0.1", second.public_port.unwrap()))
+            .await
+            .unwrap();
+        visitor.write_all(b"ping").await.unwrap();
+        let mut received = [0u8; 4];
+        tunnel.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+
+        let third = manager
+            .put("demo".to_string(), options("other"))
+            .await
+            .unwrap();
+        assert_ne!(second.public_port, third.public_port);
+    }
+
+    #[tokio::test]
+    async fn sockets_without_handshake_are_pooled() {
+        let mut manager = ClientManager::new(10);
+        let registration = manager
+            .put("demo".to_string(), Default::default())
+            .await
+            .unwrap();
+
+        let _tunnel = TcpStream::connect(("127.0.0.1", registration.port.unwrap()))
+            .await
+            .unwrap();
+
+        let client = manager.clients.get("demo").unwrap();
+        for _ in 0..50 {
+            if client.lock().await.connected_sockets().await == 1 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("socket should be pooled without a handshake");
+    }
+
+    #[tokio::test]
+    async fn only_owner_can_claim_url() {
+        let mut manager = ClientManager::new(10);
+        let registration = manager
+            .put(
+                "demo".to_string(),
+                TunnelOptions {
+                    owner: Some("kai".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        assert!(manager.can_claim("demo", Some("kai"), None).await);
+        assert!(!manager.can_claim("demo", Some("other"), None).await);
+        assert!(!manager.can_claim("demo", None, Some("guess")).await);
+        assert!(
+            manager
+                .can_claim("demo", None, Some(&registration.secret))
+                .await
+        );
+        assert!(manager.can_claim("free", Some("other"), None).await);
+    }
+
+    #[tokio::test]
+    async fn cleanup_timeout_is_capped() {
+        let mut manager = ClientManager::new(10);
+        manager.timings.max_cleanup_timeout = Duration::from_secs(600);
+
+        let options = |cleanup_timeout| TunnelOptions {
+            cleanup_timeout,
+            ..Default::default()
+        };
+        let registration = manager
+            .put("demo".to_string(), options(None))
+            .await
+            .unwrap();
+        assert_eq!(registration.cleanup_timeout, CLEANUP_TIMEOUT);
+        let registration = manager
+            .put("ci".to_string(), options(Some(Duration::from_secs(60))))
+            .await
+            .unwrap();
+        assert_eq!(registration.cleanup_timeout, Duration::from_secs(60));
+        let registration = manager
+            .put(
+                "long".to_string(),
+                options(Some(Duration::from_secs(86400))),
+            )
+            .await
+            .unwrap();
+        assert_eq!(registration.cleanup_timeout, Duration::from_secs(600));
+    }
+
+    #[tokio::test]
+    async fn requested_limits_are_capped() {
+        let mut manager = ClientManager::new(10);
+        manager.limits.max_body_size = 1024;
+
+        let registration = manager
+            .put(
+                "demo".to_string(),
+                TunnelOptions {
+                    max_body_size: Some(4096),
+                    upstream_timeout: Some(Duration::from_secs(5)),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(registration.limits.max_body_size, 1024);
+        assert_eq!(registration.limits.upstream_timeout, Duration::from_secs(5));
+        assert_eq!(
+            registration.limits.upgrade_idle_timeout,
+            UPGRADE_IDLE_TIMEOUT
+        );
+    }
+}
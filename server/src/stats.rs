@@ -0,0 +1,96 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::metrics::METRICS;
+
+/// A stream wrapper which counts the bytes read from and written to the inner stream,
+/// also in the `in` and `out` metrics.
+pub struct CountedStream<S> {
+    inner: S,
+    counter: Arc<AtomicU64>,
+}
+
+impl<S> CountedStream<S> {
+    pub fn new(inner: S, counter: Arc<AtomicU64>) -> Self {
+        CountedStream { inner, counter }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = buf.filled().len() - filled;
+            self.counter.fetch_add(read as u64, Ordering::Relaxed);
+            METRICS.bytes_in.inc_by(read as u64);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.counter.fetch_add(written as u64, Ordering::Relaxed);
+            METRICS.bytes_out.inc_by(written as u64);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Resident memory of the server process in bytes, only available on linux.
+pub fn memory_usage() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn counted_stream_counts_both_directions() {
+        let (a, mut b) = tokio::io::duplex(64);
+        let counter = Arc::new(AtomicU64::new(0));
+        let mut counted = CountedStream::new(a, counter.clone());
+
+        counted.write_all(b"hello").await.unwrap();
+        b.write_all(b"world!").await.unwrap();
+
+        let mut buf = [0u8; 6];
+        counted.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(counter.load(Ordering::Relaxed), 11);
+    }
+}
@@ -0,0 +1,200 @@
+//! TLS termination for the proxy and api ports with a (wildcard) certificate
+//! loaded from a directory, reloaded when the files change.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    crypto::ring,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+
+/// How often the certificate files are checked for changes.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// File names looked up in the certificate directory, in order,
+/// the first ones are what certbot writes.
+const CERT_FILES: [&str; 2] = ["fullchain.pem", "cert.pem"];
+const KEY_FILES: [&str; 2] = ["privkey.pem", "key.pem"];
+
+/// Resolves every server name to the certificate currently loaded.
+#[derive(Debug)]
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    key: RwLock<Arc<CertifiedKey>>,
+    modified: RwLock<Option<SystemTime>>,
+}
+
+impl CertResolver {
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let cert_path = find(dir, &CERT_FILES)?;
+        let key_path = find(dir, &KEY_FILES)?;
+        let key = load_certified_key(&cert_path, &key_path)?;
+        let modified = last_modified(&cert_path, &key_path);
+        log::info!(
+            "Loaded TLS certificate {} and key {}",
+            cert_path.display(),
+            key_path.display()
+        );
+
+        Ok(CertResolver {
+            cert_path,
+            key_path,
+            key: RwLock::new(Arc::new(key)),
+            modified: RwLock::new(modified),
+        })
+    }
+
+    /// Reload the certificate if the files have changed since the last load,
+    /// a broken certificate is logged and the current one kept.
+    pub fn reload_if_changed(&self) -> bool {
+        let modified = last_modified(&self.cert_path, &self.key_path);
+        if modified == *self.modified.read().unwrap() {
+            return false;
+        }
+
+        match load_certified_key(&self.cert_path, &self.key_path) {
+            Ok(key) => {
+                *self.key.write().unwrap() = Arc::new(key);
+                *self.modified.write().unwrap() = modified;
+                log::info!("Reloaded TLS certificate {}", self.cert_path.display());
+                true
+            }
+            Err(err) => {
+                log::error!("Failed to reload TLS certificate, keep the current one: {err}");
+                false
+            }
+        }
+    }
+
+    /// Check the certificate files for changes in the background.
+    pub fn watch(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                self.reload_if_changed();
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+/// Server config with the given ALPN protocols, e.g. `http/1.1`.
+pub fn server_config(
+    resolver: Arc<CertResolver>,
+    alpn_protocols: &[&[u8]],
+) -> io::Result<ServerConfig> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = alpn_protocols.iter().map(|p| p.to_vec()).collect();
+
+    Ok(config)
+}
+
+fn find(dir: &Path, names: &[&str]) -> io::Result<PathBuf> {
+    names
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("none of {:?} found in {}", names, dir.display()),
+            )
+        })
+}
+
+fn last_modified(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+    let cert = fs::metadata(cert_path)
+        .and_then(|meta| meta.modified())
+        .ok()?;
+    let key = fs::metadata(key_path)
+        .and_then(|meta| meta.modified())
+        .ok()?;
+    Some(cert.max(key))
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificate found in {}", cert_path.display()),
+        ));
+    }
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?.ok_or_else(
+        || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("no private key found in {}", key_path.display()),
+            )
+        },
+    )?;
+    let key = ring::sign::any_supported_type(&key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+    #[test]
+    fn reload_when_files_change() {
+        let dir = std::env::temp_dir().join(format!("localtunnel-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["fullchain.pem", "privkey.pem"] {
+            fs::copy(Path::new(FIXTURES).join(name), dir.join(name)).unwrap();
+        }
+
+        let resolver = CertResolver::load(&dir).unwrap();
+        assert!(!resolver.reload_if_changed());
+
+        // a broken certificate keeps the current one
+        fs::write(dir.join("fullchain.pem"), "broken").unwrap();
+        touch(&dir.join("fullchain.pem"), 30);
+        assert!(!resolver.reload_if_changed());
+
+        fs::copy(
+            Path::new(FIXTURES).join("fullchain.pem"),
+            dir.join("fullchain.pem"),
+        )
+        .unwrap();
+        touch(&dir.join("fullchain.pem"), 60);
+        assert!(resolver.reload_if_changed());
+        assert!(!resolver.reload_if_changed());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn touch(path: &Path, secs_ahead: u64) {
+        File::options()
+            .append(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(secs_ahead))
+            .unwrap();
+    }
+}
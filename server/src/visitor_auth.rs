@@ -0,0 +1,121 @@
+//! Credentials a tunnel client requires from visitors, checked on every request
+//! before a stream to the client is taken.
+//!
+//! Clients send the hex encoded sha256 of the accepted `user:password` pairs and
+//! bearer tokens as comma separated lists in the registration headers, so the
+//! server never learns them.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::auth::{constant_time_eq, file::hash};
+
+/// Registration headers carrying the hashed credentials.
+pub const VISITOR_BASIC_AUTH: &str = "x-visitor-basic-auth";
+pub const VISITOR_BEARER_TOKENS: &str = "x-visitor-bearer-tokens";
+
+const REALM: &str = "localtunnel";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VisitorAuth {
+    basic: Vec<String>,
+    bearer: Vec<String>,
+}
+
+impl VisitorAuth {
+    /// Parse the registration headers, `None` if the tunnel is public.
+    pub fn from_headers(basic: Option<&str>, bearer: Option<&str>) -> Result<Option<Self>, String> {
+        let auth = VisitorAuth {
+            basic: parse_hashes(basic)?,
+            bearer: parse_hashes(bearer)?,
+        };
+
+        Ok((!auth.basic.is_empty() || !auth.bearer.is_empty()).then_some(auth))
+    }
+
+    /// If the `Authorization` header of a visitor request carries an accepted credential.
+    pub fn authorize(&self, authorization: Option<&str>) -> bool {
+        let Some((scheme, value)) = authorization.and_then(|value| value.split_once(' ')) else {
+            return false;
+        };
+        let value = value.trim();
+
+        let (accepted, credential) = if scheme.eq_ignore_ascii_case("basic") {
+            let Some(decoded) = STANDARD
+                .decode(value)
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+            else {
+                return false;
+            };
+            (&self.basic, decoded)
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            (&self.bearer, value.to_string())
+        } else {
+            return false;
+        };
+
+        let hash = hash(&credential);
+        accepted
+            .iter()
+            .any(|expected| constant_time_eq(expected.as_bytes(), hash.as_bytes()))
+    }
+
+    /// Value of the `WWW-Authenticate` header sent with a 401.
+    pub fn challenge(&self) -> String {
+        let mut challenges = vec![];
+        if !self.basic.is_empty() {
+            challenges.push(format!("Basic realm=\"{REALM}\", charset=\"UTF-8\""));
+        }
+        if !self.bearer.is_empty() {
+            challenges.push(format!("Bearer realm=\"{REALM}\""));
+        }
+        challenges.join(", ")
+    }
+}
+
+fn parse_hashes(value: Option<&str>) -> Result<Vec<String>, String> {
+    let Some(value) = value else {
+        return Ok(vec![]);
+    };
+
+    value
+        .split(',')
+        .map(|hash| hash.trim().to_ascii_lowercase())
+        .map(|hash| {
+            if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                Ok(hash)
+            } else {
+                Err(format!("{hash} is not a hex encoded sha256"))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authorize_visitor_works() {
+        let auth = VisitorAuth::from_headers(
+            Some(&hash("kai:secret")),
+            Some(&format!("{},{}", hash("token1"), hash("token2"))),
+        )
+        .unwrap()
+        .unwrap();
+
+        let basic = format!("Basic {}", STANDARD.encode("kai:secret"));
+        assert!(auth.authorize(Some(&basic)));
+        assert!(auth.authorize(Some("bearer token2")));
+        assert!(!auth.authorize(Some(&format!("Basic {}", STANDARD.encode("kai:wrong")))));
+        assert!(!auth.authorize(Some("Bearer token3")));
+        assert!(!auth.authorize(None));
+        assert_eq!(
+            auth.challenge(),
+            "Basic realm=\"localtunnel\", charset=\"UTF-8\", Bearer realm=\"localtunnel\""
+        );
+
+        assert_eq!(VisitorAuth::from_headers(None, None), Ok(None));
+        assert!(VisitorAuth::from_headers(Some("kai:secret"), None).is_err());
+    }
+}